@@ -1,246 +1,905 @@
-use crate::shared::{PlayerInfo, PlayerInfoArray, WorkerMessage, VEC_PACKET_SIZE};
-use rrplug::prelude::wait;
+use crate::shared::{
+    client_handshake, is_newer, is_timeout, join_timeout, sanitize_name, Capabilities, Closer,
+    Connection, ConnectionExt, Datagram, Dialer, Extrapolation, Ghost, GhostSlots, HandshakeError,
+    Heartbeat, Interpolation, Login, Message, PlayerId, PlayerInfo, Snapshot, SnapshotDelta,
+    SnapshotHistory, Stamper, TickRate, Timeouts, WorkerMessage, HANDSHAKE_TIMEOUT,
+    MAX_DATAGRAM_SIZE, SHUTDOWN_TIMEOUT,
+};
 use std::{
-    io::{Read, Write},
-    net::TcpStream,
-    ops::Deref,
+    collections::BTreeMap,
+    fmt::{self, Display},
+    io, mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
     sync::{
-        mpsc::{self, Receiver, Sender},
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
         Arc, Mutex, RwLock,
     },
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
+/// how long the worker waits before trying to get a lost connection back
+const RECONNECT_BACKOFF: Duration = Duration::from_millis(500);
+/// the wait doubles after every failed try up to this
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+/// how often the worker looks for new jobs while a connection is being opened
+const DIAL_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// where the client is at with its connection
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ConnectionState {
+    #[default]
+    Idle,
+    /// opening the connection
+    Connecting,
+    /// waiting for the server to let us in
+    Handshaking,
+    Connected,
+    /// lost the connection and trying to get it back
+    Reconnecting,
+    /// gave up, either the server turned us away or there was nothing to reconnect to
+    Failed(String),
+    /// the server closed the connection and told us why, it isn't tried again
+    Disconnected(String),
+}
+
+impl Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Idle => write!(f, "idle"),
+            Self::Connecting => write!(f, "connecting"),
+            Self::Handshaking => write!(f, "handshaking"),
+            Self::Connected => write!(f, "connected"),
+            Self::Reconnecting => write!(f, "reconnecting"),
+            Self::Failed(reason) => write!(f, "failed : {reason}"),
+            Self::Disconnected(reason) => write!(f, "disconnected by server : {reason}"),
+        }
+    }
+}
+
+/// what the client knows about everyone else on the server
+#[derive(Debug, Default)]
+pub struct Roster {
+    pub id: Option<PlayerId>,
+    pub state: ConnectionState,
+    /// the name we introduce ourselves with
+    pub name: String,
+    pub names: BTreeMap<PlayerId, String>,
+    /// everyone but us as of the last snapshot
+    pub positions: Vec<(PlayerId, PlayerInfo)>,
+    /// the tick of the last snapshot
+    pub tick: Option<u32>,
+    /// the server's clock when the last snapshot was taken
+    pub server_time: Duration,
+    /// where everyone was on the last few snapshots, ghosts are drawn from this
+    pub interpolation: Interpolation,
+}
+
+impl Roster {
+    /// snapshots that are older than the last one got overtaken on the way and are dropped
+    pub fn apply(&mut self, snapshot: Snapshot) {
+        if matches!(self.tick, Some(last) if !is_newer(snapshot.tick, last)) {
+            return;
+        }
+
+        self.tick = Some(snapshot.tick);
+        self.server_time = snapshot.time;
+        self.positions = snapshot
+            .players
+            .into_iter()
+            .filter(|(id, _)| Some(*id) != self.id)
+            .collect();
+        self.interpolation
+            .push(snapshot.time, &self.positions, Instant::now());
+    }
+}
+
+fn set_state(players: &Arc<RwLock<Roster>>, state: ConnectionState) {
+    if let Ok(mut players) = players.write() {
+        players.state = state;
+    }
+}
+
 #[derive(Debug)]
 pub struct PlayerMirrorClient {
-    pub player_positons: Arc<RwLock<PlayerInfoArray>>, // max 15 players
+    pub players: Arc<RwLock<Roster>>,
     connnected: bool,
+    connect_timeout: Duration,
+    /// in hz, shared with the worker so changes apply right away
+    tick_rate: Arc<AtomicU32>,
     job_send: Mutex<Sender<WorkerMessage>>,
     pos_send: Mutex<Sender<PlayerInfo>>,
     worker: PacketWorker,
+    ghosts: GhostSlots,
+    stamper: Stamper,
 }
 
 impl PlayerMirrorClient {
     pub fn new() -> Self {
-        let info = PlayerInfo::default();
-
-        let player_postions = (0..16)
-            .map(|_| info.clone())
-            .collect::<Vec<PlayerInfo>>()
-            .try_into()
-            .unwrap();
+        Self::with_timeouts(Timeouts::default())
+    }
 
-        let player_positions = Arc::new(RwLock::new(player_postions));
+    pub fn with_timeouts(timeouts: Timeouts) -> Self {
+        let players = Arc::new(RwLock::new(Roster::default()));
 
         let (job_send, job_recv) = mpsc::channel();
         let (pos_send, pos_recv) = mpsc::channel();
 
-        let worker = PacketWorker::new(job_recv, player_positions.clone(), pos_recv);
+        let tick_rate = Arc::new(AtomicU32::new(TickRate::default().hz()));
+
+        let worker = PacketWorker::new(
+            job_recv,
+            players.clone(),
+            pos_recv,
+            timeouts,
+            tick_rate.clone(),
+        );
 
         Self {
-            player_positons: player_positions,
+            players,
             connnected: false,
+            connect_timeout: timeouts.connect,
+            tick_rate,
             job_send: Mutex::new(job_send),
             pos_send: Mutex::new(pos_send),
             worker,
+            ghosts: GhostSlots::new(),
+            stamper: Stamper::new(),
         }
     }
 
+    /// returns right away, the worker opens the connection and `state` tells how that went
     pub fn connect(&mut self, address: String) -> Result<(), String> {
-        if self.connnected {
-            self.job_send
-                .lock()
-                .expect("lock not acquired")
-                .send(WorkerMessage::EndJob)
-                .unwrap();
-        }
+        self.connect_through(Dialer::tcp(address, self.connect_timeout))
+    }
 
-        self.connnected = true;
+    /// like `connect` but the connection comes from `dialer`, which is also used to get it back once it's lost
+    pub fn connect_through(&mut self, dialer: Dialer) -> Result<(), String> {
+        self.send_job(WorkerMessage::Connect(dialer))
+    }
 
-        match TcpStream::connect(address) {
-            Ok(conn) => {
-                self.job_send
-                    .lock()
-                    .expect("lock not acquired")
-                    .send(WorkerMessage::Work(conn))
-                    .unwrap();
-                Ok(())
-            }
-            Err(err) => Err(err.to_string()),
-        }
+    /// drops the current connection and lets the worker run the handshake over `connection`
+    ///
+    /// once it's lost the worker keeps trying to get a new one from `dialer`
+    pub fn connect_with(
+        &mut self,
+        connection: Box<dyn Connection>,
+        dialer: Option<Dialer>,
+    ) -> Result<(), String> {
+        self.send_job(WorkerMessage::Work(connection, dialer))
+    }
+
+    /// how long opening a connection can take, only takes effect on the next `connect`
+    pub fn set_connect_timeout(&mut self, timeout: Duration) {
+        self.connect_timeout = timeout
+    }
+
+    /// how often our position goes out
+    pub fn set_tick_rate(&self, tick_rate: TickRate) {
+        self.tick_rate.store(tick_rate.hz(), Ordering::Relaxed)
+    }
+
+    /// how far ghosts keep going when the server is late
+    pub fn set_extrapolation(&self, extrapolation: Extrapolation) -> Result<(), &'static str> {
+        self.players
+            .write()
+            .or(Err("can't have locks in ohio"))?
+            .interpolation
+            .set_extrapolation(extrapolation);
+
+        Ok(())
+    }
+
+    /// drops the current connection before handing the worker its next one
+    fn send_job(&mut self, job: WorkerMessage) -> Result<(), String> {
+        self.shutdown();
+
+        self.job_send
+            .lock()
+            .expect("lock not acquired")
+            .send(job)
+            .map_err(|err| err.to_string())?;
+
+        self.connnected = true;
+        Ok(())
     }
 
     pub fn shutdown(&mut self) {
         if self.connnected {
-            self.job_send
+            _ = self
+                .job_send
                 .lock()
                 .expect("lock not acquired")
-                .send(WorkerMessage::EndJob)
-                .unwrap();
+                .send(WorkerMessage::EndJob);
         }
 
         self.connnected = false
     }
 
+    pub fn state(&self) -> ConnectionState {
+        self.players
+            .read()
+            .map(|players| players.state.clone())
+            .unwrap_or_else(|err| ConnectionState::Failed(err.to_string()))
+    }
+
+    /// `false` until the handshake is done and while a lost connection is being retried
     pub fn is_connected(&self) -> bool {
-        self.connnected
+        self.state() == ConnectionState::Connected
+    }
+
+    /// everyone else on the server, each bound to the same dummy for as long as they stay
+    ///
+    /// positions are blended between snapshots so this should be called every frame
+    pub fn get_other_positions(&mut self) -> Result<Vec<Ghost>, &'static str> {
+        let mut players = self.players.write().or(Err("can't have locks in ohio"))?;
+        let positions = players.interpolation.sample(Instant::now());
+
+        Ok(self.ghosts.ghosts(positions, |id| {
+            players
+                .names
+                .get(&id)
+                .cloned()
+                .unwrap_or_else(|| format!("player {id}"))
+        }))
+    }
+
+    /// dummies of players that left since the last call, these should be hidden
+    pub fn take_left_ghosts(&mut self) -> Vec<i32> {
+        self.ghosts.take_released()
     }
 
-    pub fn get_other_positions(&self) -> PlayerInfoArray {
-        self.player_positons.read().unwrap().deref().clone()
+    /// every dummy that's showing or still has to be hidden, used when switching modes
+    pub fn release_ghosts(&mut self) -> Vec<i32> {
+        self.ghosts.release_all()
+    }
+
+    /// dummies from somewhere else that get hidden with the next `take_left_ghosts`
+    pub fn hide_ghosts(&mut self, indexes: Vec<i32>) {
+        self.ghosts.hide(indexes)
+    }
+
+    /// used for the next connection, the server picks a name if this is empty
+    pub fn set_name(&self, name: &str) -> Result<(), &'static str> {
+        self.players
+            .write()
+            .or(Err("can't have locks in ohio"))?
+            .name = sanitize_name(name).unwrap_or_default();
+
+        Ok(())
     }
 
     pub fn push_position(&self, info: PlayerInfo) -> Result<(), &'static str> {
         self.pos_send
             .lock()
             .expect("lock not acquired")
-            .send(info)
+            .send(self.stamper.stamp(info))
             .or(Err("can't send stuff"))
     }
 }
 
 impl Drop for PlayerMirrorClient {
     fn drop(&mut self) {
-        let lock_poision = self.player_positons.clone();
-
-        thread::spawn(move || {
-            #[allow(unused_variables)]
-            let lock = lock_poision.write().unwrap();
-            panic!();
-        }); // this will poision the lock making it invalid and forcing thread to stop
+        // also ends the connection, the worker itself gets joined once it's dropped
+        if let Ok(jobs) = self.job_send.lock() {
+            _ = jobs.send(WorkerMessage::Death);
+        }
+    }
+}
 
-        let lock = self.job_send.lock().unwrap();
+/// the jobs sent to the worker, anything that shows up in the middle of a connection is kept for later
+#[derive(Debug)]
+struct Jobs {
+    receiver: Receiver<WorkerMessage>,
+    pending: Option<WorkerMessage>,
+}
 
-        _ = lock.send(WorkerMessage::EndJob);
-        _ = lock.send(WorkerMessage::Death);
+impl Jobs {
+    /// blocks until there is a job, `None` once nobody can send any more
+    fn next(&mut self) -> Option<WorkerMessage> {
+        self.pending.take().or_else(|| self.receiver.recv().ok())
+    }
 
-        _ = self.worker;
+    /// waits up to `timeout` for anything that should end the current connection
+    fn interrupted(&mut self, timeout: Duration) -> bool {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(WorkerMessage::EndJob) => true,
+            Ok(job) => {
+                self.pending = Some(job);
+                true
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => {
+                self.pending = Some(WorkerMessage::Death);
+                true
+            }
+        }
     }
 }
 
+/// how a dial on its own thread went, see `PacketWorker::dial`
+enum Dialed {
+    Connected(Box<dyn Connection>),
+    Failed(io::Error),
+    /// a new job came in or the client is shutting down
+    Interrupted,
+}
+
+/// how the worker got off a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Ended {
+    /// a new job came in or the client is shutting down
+    Interrupted,
+    /// the server turned us away, trying again won't help
+    Rejected(String),
+    /// it broke before the handshake was done
+    Failed(String),
+    /// it was up and broke
+    Lost(String),
+    /// the server closed it on purpose
+    Disconnected(String),
+}
+
 #[derive(Debug)]
 struct PacketWorker {
     thread: Option<JoinHandle<()>>,
+    /// closes whatever connection the worker is on so it can't hold up shutting down
+    closer: Arc<Mutex<Option<Closer>>>,
 }
 
 impl PacketWorker {
     fn new(
         jobs: Receiver<WorkerMessage>,
-        positions: Arc<RwLock<PlayerInfoArray>>,
+        players: Arc<RwLock<Roster>>,
         local_positions_recv: Receiver<PlayerInfo>,
+        timeouts: Timeouts,
+        tick_rate: Arc<AtomicU32>,
     ) -> Self {
+        let closer = Arc::new(Mutex::new(None));
+        let jobs = Jobs {
+            receiver: jobs,
+            pending: None,
+        };
+
         Self {
-            thread: Some(thread::spawn(move || {
-                Self::job_handler(jobs, positions, local_positions_recv)
+            thread: Some(thread::spawn({
+                let closer = closer.clone();
+                move || {
+                    Self::job_handler(
+                        jobs,
+                        players,
+                        local_positions_recv,
+                        closer,
+                        timeouts,
+                        tick_rate,
+                    )
+                }
             })),
+            closer,
         }
     }
 
     fn job_handler(
-        jobs: Receiver<WorkerMessage>,
-        positions: Arc<RwLock<PlayerInfoArray>>,
+        mut jobs: Jobs,
+        players: Arc<RwLock<Roster>>,
         local_positions_recv: Receiver<PlayerInfo>,
+        closer: Arc<Mutex<Option<Closer>>>,
+        timeouts: Timeouts,
+        tick_rate: Arc<AtomicU32>,
     ) {
-        loop {
-            let message = jobs.recv().unwrap(); // should never panic if it does
-                                                // managing the error is needing or else the mutex might get poisoned
+        while let Some(message) = jobs.next() {
+            let (connection, mut dialer) = match message {
+                WorkerMessage::Work(connection, dialer) => (connection, dialer),
+                WorkerMessage::Connect(dialer) => {
+                    set_state(&players, ConnectionState::Connecting);
 
-            let stream = match message {
-                WorkerMessage::Work(stream) => stream,
+                    let mut dialer = Some(dialer);
+                    match Self::dial(&mut dialer, &mut jobs) {
+                        Dialed::Connected(connection) => (connection, dialer),
+                        Dialed::Failed(err) => {
+                            log::error!("failed to connect : {err}");
+                            set_state(&players, ConnectionState::Failed(err.to_string()));
+                            continue;
+                        }
+                        Dialed::Interrupted => {
+                            set_state(&players, ConnectionState::Idle);
+                            continue;
+                        }
+                    }
+                }
+                WorkerMessage::EndJob => {
+                    set_state(&players, ConnectionState::Idle);
+                    continue;
+                }
                 WorkerMessage::Death => break,
-                _ => continue,
             };
 
-            log::info!("connection created for Stream");
+            // only a reconnect asks for the old id back, another server could have given it out
+            if let Ok(mut players) = players.write() {
+                players.id = None;
+            }
 
-            match stream.set_nonblocking(false) {
-                Ok(_) => log::info!("stream is blocking"),
-                Err(err) => {
-                    log::error!("stream is non blocking {err}");
-                    continue;
+            let mut connection = Some(connection);
+            let mut backoff = RECONNECT_BACKOFF;
+
+            loop {
+                if let Some(connection) = connection.take() {
+                    log::info!("connection created for Stream");
+                    set_state(&players, ConnectionState::Handshaking);
+
+                    if let Ok(mut closer) = closer.lock() {
+                        *closer = connection.closer();
+                    }
+
+                    // dropping the worker only closes the connection it can see, this one could have come too late
+                    let ended = if jobs.interrupted(Duration::ZERO) {
+                        Ended::Interrupted
+                    } else {
+                        Self::work(
+                            connection,
+                            &players,
+                            &local_positions_recv,
+                            &mut jobs,
+                            timeouts,
+                            &tick_rate,
+                        )
+                    };
+
+                    if let Ok(mut closer) = closer.lock() {
+                        *closer = None;
+                    }
+
+                    if let Ok(mut players) = players.write() {
+                        players.names.clear();
+                        players.positions.clear(); // so every ghost gets hidden
+                        players.tick = None; // the next server counts from scratch
+                        players.interpolation.clear();
+                    }
+
+                    let reason = match ended {
+                        Ended::Interrupted => {
+                            log::info!("connection closed for client");
+                            set_state(&players, ConnectionState::Idle);
+                            break;
+                        }
+                        Ended::Rejected(reason) => {
+                            log::error!("connection terminated for client : {reason}");
+                            set_state(&players, ConnectionState::Failed(reason));
+                            break;
+                        }
+                        Ended::Disconnected(reason) => {
+                            log::warn!("disconnected by server : {reason}");
+                            set_state(&players, ConnectionState::Disconnected(reason));
+                            break;
+                        }
+                        Ended::Failed(reason) => reason,
+                        Ended::Lost(reason) => {
+                            backoff = RECONNECT_BACKOFF;
+                            reason
+                        }
+                    };
+
+                    log::error!("connection terminated for client : {reason}");
+
+                    if dialer.is_none() {
+                        set_state(&players, ConnectionState::Failed(reason));
+                        break;
+                    }
                 }
-            }
 
-            wait(10);
+                if dialer.is_none() {
+                    break;
+                }
+                set_state(&players, ConnectionState::Reconnecting);
+
+                log::info!("reconnecting in {backoff:?}");
 
-            Self::work(stream, &positions, &local_positions_recv, &jobs);
+                if jobs.interrupted(backoff) {
+                    set_state(&players, ConnectionState::Idle);
+                    break;
+                }
+                backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
 
-            log::error!("connection terminated for client");
+                match Self::dial(&mut dialer, &mut jobs) {
+                    Dialed::Connected(new) => connection = Some(new),
+                    Dialed::Failed(err) => log::warn!("couldn't reconnect : {err}"),
+                    Dialed::Interrupted => {
+                        set_state(&players, ConnectionState::Idle);
+                        break;
+                    }
+                }
+            }
         }
 
         log::warn!("worker was told to stop");
     }
 
+    /// opens a connection on a thread of its own so new jobs and shutting down don't have to wait for it
+    ///
+    /// the dialer is taken out of `dialer` while it runs, an interrupted dial is told to give up and
+    /// whatever it still opens gets dropped
+    fn dial(dialer: &mut Option<Dialer>, jobs: &mut Jobs) -> Dialed {
+        let Some(mut taken) = dialer.take() else {
+            return Dialed::Failed(io::ErrorKind::NotConnected.into());
+        };
+
+        let aborted = Arc::new(AtomicBool::new(false));
+        let (dialed_send, dialed) = mpsc::channel();
+        thread::spawn({
+            let aborted = aborted.clone();
+            move || {
+                let result = taken.dial(&aborted);
+                _ = dialed_send.send((taken, result));
+            }
+        });
+
+        loop {
+            match dialed.try_recv() {
+                Ok((taken, result)) => {
+                    *dialer = Some(taken);
+                    return match result {
+                        Ok(connection) => Dialed::Connected(connection),
+                        Err(err) => Dialed::Failed(err),
+                    };
+                }
+                Err(TryRecvError::Disconnected) => {
+                    return Dialed::Failed(io::Error::other("dialer panicked"))
+                }
+                Err(TryRecvError::Empty) => {}
+            }
+
+            if jobs.interrupted(DIAL_POLL_INTERVAL) {
+                aborted.store(true, Ordering::Relaxed);
+                return Dialed::Interrupted;
+            }
+        }
+    }
+
     fn work(
-        mut stream: TcpStream,
-        positions: &Arc<RwLock<PlayerInfoArray>>,
+        mut stream: Box<dyn Connection>,
+        players: &Arc<RwLock<Roster>>,
         local_positions_recv: &Receiver<PlayerInfo>,
-        termination_notice: &Receiver<WorkerMessage>,
-    ) {
-        let mut last_known_local_position: PlayerInfo = PlayerInfo::default();
+        termination_notice: &mut Jobs,
+        timeouts: Timeouts,
+        tick_rate: &AtomicU32,
+    ) -> Ended {
+        if let Err(err) = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)) {
+            return Ended::Failed(format!("couldn't set handshake timeout : {err}"));
+        }
+
+        let login = match players.read() {
+            Ok(players) => Login {
+                name: players.name.clone(),
+                previous_id: players.id,
+            },
+            Err(err) => {
+                return Ended::Failed(format!("couldn't get lock : {err}"));
+            }
+        };
+
+        let session = match client_handshake(stream.as_mut(), &login) {
+            Ok(session) => session,
+            Err(err @ HandshakeError::Io(_)) => {
+                return Ended::Failed(format!("handshake failed : {err}"));
+            }
+            Err(err) => {
+                return Ended::Rejected(format!("handshake failed : {err}"));
+            }
+        };
+
+        log::info!(
+            "handshake completed with {:?} as player {}",
+            session.capabilities,
+            session.id
+        );
+
+        match players.write() {
+            Ok(mut players) => {
+                *players = Roster {
+                    id: Some(session.id),
+                    state: ConnectionState::Connected,
+                    name: login.name,
+                    interpolation: mem::take(&mut players.interpolation),
+                    ..Default::default()
+                }
+            }
+            Err(err) => {
+                return Ended::Failed(format!("couldn't get lock : {err}"));
+            }
+        }
+
+        if let Err(err) = timeouts.apply(stream.as_mut()) {
+            return Ended::Lost(format!("couldn't set timeouts : {err}"));
+        }
+
+        let mut datagrams = None;
+
+        if session.capabilities.contains(Capabilities::UDP_POSITIONS) {
+            let udp = stream
+                .peer_addr()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "peer has no address"))
+                .and_then(|server| PositionDatagrams::connect(server, session.id));
+
+            match udp {
+                Ok(udp) => datagrams = Some(udp),
+                Err(err) => log::warn!("couldn't set up udp, positions stay on tcp : {err}"),
+            }
+        }
+
+        let ended = Self::serve(
+            stream.as_mut(),
+            players,
+            local_positions_recv,
+            termination_notice,
+            timeouts,
+            tick_rate,
+            datagrams,
+        );
+
+        stream.close();
+        ended
+    }
+
+    /// sends our position every tick and handles whatever the server sends in between, neither waits on the other
+    fn serve(
+        stream: &mut dyn Connection,
+        players: &Arc<RwLock<Roster>>,
+        local_positions_recv: &Receiver<PlayerInfo>,
+        termination_notice: &mut Jobs,
+        timeouts: Timeouts,
+        tick_rate: &AtomicU32,
+        mut datagrams: Option<PositionDatagrams>,
+    ) -> Ended {
+        let mut local_pos = PlayerInfo::default();
+        let mut heartbeat = Heartbeat::new(timeouts);
+        let mut history = SnapshotHistory::new();
+        let mut next_send = Instant::now();
+
+        // the worker waits on the datagrams then, the stream only gets checked for what's already there
+        if datagrams.is_some() {
+            if let Err(err) = stream.set_nonblocking(true) {
+                return Ended::Lost(format!("couldn't make the stream nonblocking : {err}"));
+            }
+        }
 
         loop {
-            if let Ok(WorkerMessage::EndJob) = termination_notice.try_recv() {
-                return;
+            if termination_notice.interrupted(Duration::ZERO) {
+                _ = stream.send(&Message::Disconnect("client left".to_string()));
+                _ = stream.flush();
+                return Ended::Interrupted;
             }
 
-            let local_pos = local_positions_recv
-                .try_recv()
-                .unwrap_or(last_known_local_position.clone());
+            if Instant::now() >= next_send {
+                // the game pushes one every frame but only the newest one matters
+                while let Ok(info) = local_positions_recv.try_recv() {
+                    local_pos = info;
+                }
 
-            if last_known_local_position != local_pos {
-                last_known_local_position = local_pos.clone();
+                match datagrams.as_mut() {
+                    Some(datagrams) => {
+                        if let Err(err) = datagrams.send(local_pos.clone()) {
+                            log::warn!("failed to send datagram : {err}");
+                        }
+                    }
+                    None => {
+                        if let Err(err) = stream.send(&Message::Position(local_pos.clone())) {
+                            return Ended::Lost(format!("failed to send : {err}"));
+                        }
+                    }
+                }
+
+                let tick_rate =
+                    TickRate::new(tick_rate.load(Ordering::Relaxed)).unwrap_or_default();
+                next_send = tick_rate.next_tick(next_send);
             }
 
-            {
-                let sendpackets = match bincode::serialize(&local_pos) {
-                    Ok(s) => s,
-                    Err(err) => {
-                        log::error!("couldn't serialize packets : {err}");
-                        return;
+            let read_until = match datagrams.as_mut() {
+                Some(datagrams) => {
+                    match datagrams.next_snapshot(next_send) {
+                        Ok(Some(delta)) => {
+                            let applied = match players.write() {
+                                Ok(mut players) => {
+                                    Self::apply_snapshot(stream, &mut players, &mut history, delta)
+                                }
+                                Err(err) => Err(format!("couldn't get lock : {err}")),
+                            };
+
+                            if let Err(err) = applied {
+                                return Ended::Lost(err);
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(err) => log::warn!("failed to receive datagram : {err}"),
+                    }
+
+                    if let Err(err) = stream.flush() {
+                        return Ended::Lost(format!("failed to send : {err}"));
                     }
-                };
 
-                match stream.write_all(&sendpackets) {
-                    Ok(_) => {}
-                    Err(err) => log::error!("failed to write all : {err}"),
+                    None
                 }
+                None => Some(next_send),
+            };
+
+            if let Some(ended) =
+                Self::receive(stream, players, &mut heartbeat, &mut history, read_until)
+            {
+                return ended;
+            }
+        }
+    }
+
+    /// rebuilds a snapshot and acks it, asks for a full one if it was made against one we don't have
+    fn apply_snapshot(
+        stream: &mut dyn Connection,
+        players: &mut Roster,
+        history: &mut SnapshotHistory,
+        delta: SnapshotDelta,
+    ) -> Result<(), String> {
+        let reply = match history.apply(delta) {
+            Ok(snapshot) => {
+                let tick = snapshot.tick;
+                players.apply(snapshot);
+                Message::Ack(tick)
+            }
+            Err(err) => {
+                log::warn!("{err}");
+                Message::RequestBaseline
             }
+        };
 
-            let mut buffer = vec![0; VEC_PACKET_SIZE];
+        stream
+            .send(&reply)
+            .map_err(|err| format!("failed to send : {err}"))
+    }
 
-            match stream.read(&mut buffer) {
-                Ok(_) => {}
+    /// handles what the server sent until `until`, or only what's already there on a nonblocking stream
+    ///
+    /// returns how the connection ended once it's over
+    fn receive(
+        stream: &mut dyn Connection,
+        players: &Arc<RwLock<Roster>>,
+        heartbeat: &mut Heartbeat,
+        history: &mut SnapshotHistory,
+        until: Option<Instant>,
+    ) -> Option<Ended> {
+        loop {
+            match heartbeat.poll() {
+                Ok(Some(ping)) => {
+                    if let Err(err) = stream.send(&ping) {
+                        return Some(Ended::Lost(format!("failed to send ping : {err}")));
+                    }
+                }
+                Ok(None) => {}
                 Err(err) => {
-                    log::error!("failed to read : {err}");
-                    return;
+                    return Some(Ended::Lost(format!("server timed out : {err}")));
                 }
             }
 
-            let recvpackets: Vec<PlayerInfo> = match bincode::deserialize(&buffer) {
+            if let Some(until) = until {
+                // a zero timeout means blocking forever
+                let wait = until
+                    .saturating_duration_since(Instant::now())
+                    .max(Duration::from_millis(1));
+
+                if let Err(err) = stream.set_read_timeout(Some(wait)) {
+                    return Some(Ended::Lost(format!("couldn't set read timeout : {err}")));
+                }
+            }
+
+            let message = match stream.recv_message() {
+                Ok(message) => {
+                    heartbeat.received();
+                    match message {
+                        Some(message) => message,
+                        None => continue,
+                    }
+                }
+                Err(err) if is_timeout(&err) => return None,
+                Err(err) => {
+                    return Some(Ended::Lost(format!("failed to receive : {err}")));
+                }
+            };
+
+            let mut players = match players.write() {
                 Ok(p) => p,
                 Err(err) => {
-                    log::error!("couldn't deserialize packet : {err}");
-                    return;
+                    return Some(Ended::Lost(format!("couldn't get lock : {err}")));
                 }
             };
 
-            {
-                let mut positions = match positions.write() {
-                    Ok(p) => p,
-                    Err(err) => {
-                        log::error!("couldn't get lock : {err}");
-                        return;
+            match message {
+                Message::Snapshot(delta) => {
+                    if let Err(err) = Self::apply_snapshot(stream, &mut players, history, delta) {
+                        return Some(Ended::Lost(err));
+                    }
+                }
+                Message::Ping(nonce) => {
+                    if let Err(err) = stream.send(&Message::Pong(nonce)) {
+                        return Some(Ended::Lost(format!("failed to send : {err}")));
                     }
-                };
-
-                match recvpackets.try_into() {
-                    Ok(p) => *positions = p,
-                    Err(_) => log::error!("failed to set new positions"),
                 }
+                Message::Join { id, name } => {
+                    log::info!("{name} joined as player {id}");
+                    players.names.insert(id, name);
+                }
+                Message::Leave(id) => {
+                    log::info!("player {id} left");
+                    players.names.remove(&id);
+                    players.positions.retain(|(other, _)| *other != id);
+                    players.interpolation.remove(id);
+                }
+                Message::Chat { id, text } => log::info!("{id} says : {text}"),
+                Message::Disconnect(reason) => return Some(Ended::Disconnected(reason)),
+                Message::Pong(_)
+                | Message::Position(_)
+                | Message::Ack(_)
+                | Message::RequestBaseline => {}
             }
 
-            wait(100);
+            if matches!(until, Some(until) if Instant::now() >= until) {
+                return None;
+            }
+        }
+    }
+}
+
+/// the udp side of a connection that agreed on `Capabilities::UDP_POSITIONS`
+#[derive(Debug)]
+struct PositionDatagrams {
+    socket: UdpSocket,
+    id: PlayerId,
+    buffer: Vec<u8>,
+}
+
+impl PositionDatagrams {
+    fn connect(server: SocketAddr, id: PlayerId) -> io::Result<Self> {
+        let local: SocketAddr = match server {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(server)?;
+
+        Ok(Self {
+            socket,
+            id,
+            buffer: vec![0; MAX_DATAGRAM_SIZE],
+        })
+    }
+
+    fn send(&mut self, info: PlayerInfo) -> Result<(), String> {
+        let bytes = bincode::serialize(&Datagram::Position { id: self.id, info })
+            .map_err(|err| err.to_string())?;
+
+        self.socket
+            .send(&bytes)
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+
+    /// waits for the next snapshot until `until`, ones that are already there come out right away
+    ///
+    /// older ones than what we have are dropped later on like any other snapshot
+    fn next_snapshot(&mut self, until: Instant) -> io::Result<Option<SnapshotDelta>> {
+        loop {
+            // a zero timeout means blocking forever
+            let wait = until
+                .saturating_duration_since(Instant::now())
+                .max(Duration::from_millis(1));
+            self.socket.set_read_timeout(Some(wait))?;
+
+            let len = match self.socket.recv(&mut self.buffer) {
+                Ok(len) => len,
+                Err(err) if is_timeout(&err) => return Ok(None),
+                Err(err) => return Err(err),
+            };
+
+            if let Ok(Datagram::Snapshot(snapshot)) = bincode::deserialize(&self.buffer[..len]) {
+                return Ok(Some(snapshot));
+            }
         }
     }
 }
@@ -249,8 +908,355 @@ impl Drop for PacketWorker {
     fn drop(&mut self) {
         log::warn!("Shutting down client connection thread");
 
-        if let Some(thread) = self.thread.take() {
-            thread.join().unwrap();
+        let Some(thread) = self.thread.take() else {
+            return;
+        };
+
+        // the handshake doesn't look for jobs, this wakes it up instead of waiting out its timeout
+        if let Some(closer) = self.closer.lock().ok().and_then(|mut closer| closer.take()) {
+            closer.close();
         }
+
+        if join_timeout(thread, SHUTDOWN_TIMEOUT).is_err() {
+            log::error!("client connection thread didn't stop, leaving it behind");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::{
+        tests::{wait_for, wait_until},
+        Action, FramedStream, MemoryConnection, SerializableVector3, ServerHandshake,
+    };
+    use std::{
+        io::Read,
+        net::TcpListener,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn silent_server_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut client = PlayerMirrorClient::with_timeouts(Timeouts {
+            read: Duration::from_millis(20),
+            write: Duration::from_secs(1),
+            ping_interval: Duration::from_millis(50),
+            peer: Duration::from_millis(200),
+            connect: Duration::from_secs(1),
+        });
+        client
+            .connect(listener.local_addr().unwrap().to_string())
+            .unwrap();
+
+        let mut stream = FramedStream::new(listener.accept().unwrap().0);
+        ServerHandshake::new(Capabilities::NONE)
+            .poll(&mut stream, |_| Ok(1))
+            .unwrap()
+            .unwrap();
+
+        // never answer anything and wait for the client to hang up
+        let start = Instant::now();
+        let mut buffer = [0; 64];
+        let mut raw = stream.get_ref().try_clone().unwrap();
+        raw.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        while let Ok(1..) = raw.read(&mut buffer) {}
+
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn snapshots_become_ghosts() {
+        let mut client = PlayerMirrorClient::new();
+        client.set_name("runner").unwrap();
+
+        let info = PlayerInfo {
+            action: Action::Crouch,
+            ..Default::default()
+        };
+        client.push_position(info.clone()).unwrap();
+
+        let (mut connection, remote) = MemoryConnection::pair();
+        client.connect_with(Box::new(remote), None).unwrap();
+        connection
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+
+        ServerHandshake::new(Capabilities::NONE)
+            .poll(&mut connection, |login| {
+                assert_eq!(login.name, "runner");
+                Ok(3)
+            })
+            .unwrap()
+            .unwrap();
+
+        connection
+            .send(&Message::Join {
+                id: 0,
+                name: "hosty".to_string(),
+            })
+            .unwrap();
+
+        // positions keep going out every tick without the server answering any of them, the same one
+        // keeps its sequence number until the game pushes another
+        let mut positions = 0;
+        while positions < 3 {
+            if let Some(Message::Position(position)) = connection.recv_message().unwrap() {
+                assert_eq!(position.action, info.action);
+                assert_eq!(position.sequence, 1);
+                positions += 1;
+            }
+        }
+        connection
+            .send(&Message::Snapshot(SnapshotDelta {
+                tick: 1,
+                changed: vec![(0, PlayerInfo::default().into()), (3, info.clone().into())],
+                ..Default::default()
+            }))
+            .unwrap();
+
+        let ghosts = wait_for(|| {
+            let ghosts = client.get_other_positions().unwrap();
+            (!ghosts.is_empty()).then_some(ghosts)
+        });
+
+        assert_eq!(client.players.read().unwrap().id, Some(3));
+        assert_eq!(
+            ghosts,
+            vec![Ghost {
+                index: 0,
+                id: 0,
+                name: "hosty".to_string(),
+                info: PlayerInfo::default(),
+            }]
+        );
+
+        client.shutdown();
+
+        loop {
+            match connection.recv_message().unwrap() {
+                Some(Message::Disconnect(reason)) => break assert_eq!(reason, "client left"),
+                _ => continue,
+            }
+        }
+    }
+
+    #[test]
+    fn lost_connections_come_back_with_the_same_id() {
+        let (remotes_send, remotes) = mpsc::channel();
+        let dialer = Dialer::new(move |_| {
+            let (connection, remote) = MemoryConnection::pair();
+            remotes_send
+                .send(connection)
+                .or(Err(io::ErrorKind::NotConnected))?;
+            Ok(Box::new(remote) as Box<dyn Connection>)
+        });
+
+        let client = {
+            let mut client = PlayerMirrorClient::new();
+            let (mut connection, remote) = MemoryConnection::pair();
+            client.connect_with(Box::new(remote), Some(dialer)).unwrap();
+            connection
+                .set_read_timeout(Some(Duration::from_secs(2)))
+                .unwrap();
+
+            ServerHandshake::new(Capabilities::NONE)
+                .poll(&mut connection, |login| {
+                    assert_eq!(login.previous_id, None);
+                    Ok(7)
+                })
+                .unwrap()
+                .unwrap();
+
+            wait_until(|| client.is_connected());
+
+            client // the server end is dropped here
+        };
+
+        let mut connection = remotes.recv_timeout(Duration::from_secs(2)).unwrap();
+        connection
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        assert!(!client.is_connected());
+
+        ServerHandshake::new(Capabilities::NONE)
+            .poll(&mut connection, |login| {
+                assert_eq!(login.previous_id, Some(7));
+                Ok(7)
+            })
+            .unwrap()
+            .unwrap();
+
+        wait_until(|| client.is_connected());
+        assert_eq!(client.players.read().unwrap().id, Some(7));
+    }
+
+    #[test]
+    fn connecting_doesnt_block() {
+        let mut client = PlayerMirrorClient::with_timeouts(Timeouts {
+            connect: Duration::from_millis(200),
+            ..Default::default()
+        });
+
+        // shouldn't route anywhere so the connect runs into its timeout
+        let start = Instant::now();
+        client.connect("10.255.255.1:9".to_string()).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        // the attempt is over one way or another once the timeout ran out
+        wait_until(|| {
+            !matches!(
+                client.state(),
+                ConnectionState::Idle | ConnectionState::Connecting
+            )
+        });
+    }
+
+    #[test]
+    fn pending_connects_dont_hold_up_dropping() {
+        // dials that block until they're told to give up
+        let (gave_up_send, gave_up) = mpsc::channel();
+        let blocking = || {
+            let gave_up_send = gave_up_send.clone();
+            Dialer::new(move |aborted| {
+                while !aborted.load(Ordering::Relaxed) {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                _ = gave_up_send.send(());
+                Err(io::ErrorKind::Interrupted.into())
+            })
+        };
+        let connecting = |client: &PlayerMirrorClient| {
+            wait_until(|| client.state() == ConnectionState::Connecting)
+        };
+
+        let mut client = PlayerMirrorClient::new();
+        client.connect_through(blocking()).unwrap();
+        connecting(&client);
+
+        // another job doesn't wait for the dial
+        client.shutdown();
+        wait_until(|| client.state() == ConnectionState::Idle);
+        gave_up.recv_timeout(SHUTDOWN_TIMEOUT).unwrap();
+
+        // and neither does switching modes
+        client.connect_through(blocking()).unwrap();
+        connecting(&client);
+        let start = Instant::now();
+        drop(client);
+        assert!(start.elapsed() < SHUTDOWN_TIMEOUT);
+        gave_up.recv_timeout(SHUTDOWN_TIMEOUT).unwrap();
+    }
+
+    #[test]
+    fn dropping_doesnt_wait_for_the_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut client = PlayerMirrorClient::new();
+        client
+            .connect(listener.local_addr().unwrap().to_string())
+            .unwrap();
+
+        // the server never answers the hello
+        let _stream = listener.accept().unwrap();
+        wait_until(|| client.state() == ConnectionState::Handshaking);
+
+        let start = Instant::now();
+        drop(client);
+        assert!(start.elapsed() < HANDSHAKE_TIMEOUT / 10);
+    }
+
+    #[test]
+    fn disconnects_from_the_server_are_final() {
+        let (dials_send, dials) = mpsc::channel();
+        let dialer = Dialer::new(move |_| {
+            _ = dials_send.send(());
+            Err(io::ErrorKind::ConnectionRefused.into())
+        });
+
+        let mut client = PlayerMirrorClient::new();
+        let (mut connection, remote) = MemoryConnection::pair();
+        client.connect_with(Box::new(remote), Some(dialer)).unwrap();
+        connection
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+
+        ServerHandshake::new(Capabilities::NONE)
+            .poll(&mut connection, |_| Ok(1))
+            .unwrap()
+            .unwrap();
+        connection
+            .send(&Message::Disconnect("server closing".to_string()))
+            .unwrap();
+
+        wait_until(|| {
+            client.state() == ConnectionState::Disconnected("server closing".to_string())
+        });
+        assert!(dials.recv_timeout(RECONNECT_BACKOFF * 2).is_err());
+    }
+
+    #[test]
+    fn stopping_while_reconnecting_goes_idle() {
+        let mut client = PlayerMirrorClient::new();
+        let (connection, remote) = MemoryConnection::pair();
+        let dialer = Dialer::new(|_| Err(io::ErrorKind::ConnectionRefused.into()));
+        client.connect_with(Box::new(remote), Some(dialer)).unwrap();
+        drop(connection); // the handshake fails so the worker starts waiting to try again
+
+        wait_until(|| client.state() == ConnectionState::Reconnecting);
+
+        client.shutdown();
+        wait_until(|| client.state() == ConnectionState::Idle);
+    }
+
+    #[test]
+    fn failed_connects_show_up_in_the_state() {
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string(); // nothing listens there anymore
+
+        let mut client = PlayerMirrorClient::new();
+        assert_eq!(client.state(), ConnectionState::Idle);
+        client.connect(address).unwrap();
+
+        wait_until(|| matches!(client.state(), ConnectionState::Failed(_)));
+
+        client.shutdown();
+        assert!(!client.is_connected());
+    }
+
+    #[test]
+    fn snapshots_apply_in_tick_order() {
+        let snapshot = |tick: u32, x: f32| Snapshot {
+            tick,
+            time: Duration::from_millis(tick as u64 * 33),
+            players: vec![
+                (
+                    0,
+                    PlayerInfo {
+                        position: SerializableVector3 { x, y: 0., z: 0. },
+                        ..Default::default()
+                    },
+                ),
+                (1, PlayerInfo::default()),
+            ],
+        };
+
+        let mut roster = Roster {
+            id: Some(1),
+            ..Default::default()
+        };
+
+        roster.apply(snapshot(u32::MAX, 1.));
+        roster.apply(snapshot(1, 2.));
+        roster.apply(snapshot(0, 3.)); // overtaken on the way
+
+        assert_eq!(roster.tick, Some(1));
+        assert_eq!(roster.server_time, Duration::from_millis(33));
+        assert_eq!(roster.positions, snapshot(1, 2.).players[..1]);
     }
 }
@@ -3,14 +3,6 @@ pub const SQURRIEL_CODE: &str = r#"
 if ( IsLobby() )
     return
 
-for(int x = 0; x < 16; x++)
-{
-    entity dummy = CreateExpensiveScriptMoverModel( $"models/humans/heroes/mlt_hero_jack.mdl", <0,0,0>, <0,0,0>, SOLID_VPHYSICS, -1 )
-    dummy.kv.skin = PILOT_SKIN_INDEX_GHOST
-    dummy.NotSolid()
-    dummy.SetScriptName(x.tostring())
-}
-
 thread void function() 
 {
     bool functionref( entity, vector ) TraceWallrun = bool function( entity player, vector side )
@@ -23,14 +15,54 @@ thread void function()
         return IsValid( results.hitEnt )
     }
 
+    bool wasAirborne = false
+    bool doubleJumped = false
+    float lastVerticalSpeed = 0.0
+
     for(;;)
     {
         entity player = GetPlayerArray()[0]
         vector origin = player.GetOrigin()
         vector angles = player.GetAngles()
+        vector velocity = player.GetVelocity()
+        float speed = Length2D( velocity )
         int action = 2
 
-        if ( player.IsWallRunning() )
+        // a second jump shows up as getting pushed up while already in the air
+        bool airborne = !player.IsOnGround() && !player.IsWallRunning()
+        if ( !airborne )
+            doubleJumped = false
+        else if ( wasAirborne && velocity.z - lastVerticalSpeed > 200 )
+            doubleJumped = true
+        wasAirborne = airborne
+        lastVerticalSpeed = velocity.z
+
+        // the numbers match the `actions!` list in shared.rs
+        if ( !IsAlive( player ) )
+        {
+            action = 16
+        }
+        else if ( player.IsPhaseShifted() )
+        {
+            action = 17
+        }
+        else if ( player.IsZiplining() )
+        {
+            action = 14
+        }
+        else if ( player.IsGrappleActive() )
+        {
+            action = 13
+        }
+        else if ( player.IsMantling() )
+        {
+            action = 12
+        }
+        else if ( player.PlayerMelee_IsAttackActive() )
+        {
+            action = 15
+        }
+        else if ( player.IsWallRunning() )
         {
             action = 4
             if ( TraceWallrun( player, player.GetRightVector() * 50 ) ) // right
@@ -42,37 +74,76 @@ thread void function()
             else if ( TraceWallrun( player, player.GetForwardVector() * -50 ) ) // back
                 action = 7
         }
+        else if ( player.IsSliding() )
+        {
+            action = 8
+        }
         else if ( player.IsCrouched() )
         {
             action = 0
         }
         else if ( !player.IsOnGround() )
         {
-            action = 3
+            action = doubleJumped ? 11 : 3
         }
-        else if ( player.GetVelocity().x > 30 || player.GetVelocity().y > 30 )
+        else if ( player.IsSprinting() )
+        {
+            action = 10
+        }
+        else if ( speed > 150 )
+        {
             action = 1
+        }
+        else if ( speed > 30 )
+        {
+            action = 9
+        }
 
-
-        MirrorPlayerRunFrame( origin, angles, action, void function( int index, vector origin, vector angles, int action )
+        MirrorPlayerRunFrame( origin, angles, velocity, action, void function( int index, vector origin, vector angles, int action, string name )
         {
-            entity dummy = GetEntByScriptName(index.tostring())
+            array<entity> dummies = GetEntArrayByScriptName(index.tostring())
+            entity dummy
+
+            if ( dummies.len() == 0 ) // dummies get created once a player needs one, there is no cap on them
+            {
+                dummy = CreateExpensiveScriptMoverModel( $"models/humans/heroes/mlt_hero_jack.mdl", origin, angles, SOLID_VPHYSICS, -1 )
+                dummy.kv.skin = PILOT_SKIN_INDEX_GHOST
+                dummy.NotSolid()
+                dummy.SetScriptName(index.tostring())
+            }
+            else
+                dummy = dummies[0]
     
-            dummy.NonPhysicsMoveTo( origin, 0.1, 0.000000000001, 0.0000000000001 )
-            dummy.NonPhysicsRotateTo( angles, 0.1, 0.000000000001, 0.0000000000001 )
+            // positions already come in smoothed every frame, easing them again would only make the ghost lag
+            dummy.SetOrigin( origin )
+            dummy.SetAngles( angles )
+            dummy.SetTitle( name ) // shows up above the ghost when looking at it
+            dummy.Show()
             
-            string anim = "ACT_MP_CROUCHWALK_FORWARD"; // default is crouch
+            // the newer actions borrow the closest anim the ghosts already played until they get checked ones of
+            // their own, anything else like actions from newer versions crouches as it always did
+            string anim = "ACT_MP_CROUCHWALK_FORWARD"
 
             switch( action )
             {
-                case 0: // slide
+                case 0: // crouch
+                case 8: // slide
+                case 16: // dead todo have a proper anim for it later
                     anim = "ACT_MP_CROUCHWALK_FORWARD"
                     break
                 case 2: // stand todo have a proper anim for it later
                 case 1: // run
+                case 9: // walk
+                case 10: // sprint
+                case 15: // melee
                     anim = "Sprint_mp_forward"
                     break
                 case 3: // jump / fall
+                case 11: // double jump
+                case 12: // mantle
+                case 13: // grapple
+                case 14: // zipline
+                case 17: // phase shift
                     anim = "jump_start"
                     break
                 case 4: // wallrun right
@@ -90,6 +161,11 @@ thread void function()
             }
 
             dummy.Anim_Play( anim )
+        },
+        void function( int index )
+        {
+            foreach ( entity dummy in GetEntArrayByScriptName(index.tostring()) )
+                dummy.Hide() // the player left
         } )
         wait 0
     };
@@ -9,7 +9,7 @@ use rrplug::{
         convar::{FCVAR_GAMEDLL, FCVAR_SPONLY},
         squirreldatatypes::SQObject,
     },
-    call_sq_object_function, sq_raise_error, sq_return_null,
+    call_sq_object_function, sq_raise_error, sq_return_null, sq_return_string,
     wrappers::{
         northstar::{EngineLoadType, PluginData, ScriptVmType},
         squirrel::{call_sq_function, compile_string},
@@ -17,12 +17,11 @@ use rrplug::{
     },
     OnceCell,
 };
-use std::sync::RwLock;
+use std::{collections::HashMap, sync::RwLock, time::Duration};
 use {
     client::PlayerMirrorClient,
     inlined_squirrel::SQURRIEL_CODE,
-    server::PlayerMirrorServer,
-    shared::{MirroringType, PlayerInfo},
+    shared::{Action, Extrapolation, MirroringType, PlayerInfo, PositionTransport, TickRate},
 };
 
 mod client;
@@ -48,6 +47,7 @@ impl Plugin for PlayerMirror {
         plugin_data
             .register_sq_functions(info_wait_for_full_startup)
             .unwrap();
+        plugin_data.register_sq_functions(info_get_status).unwrap();
 
         self.mirrortype
             .set(RwLock::new(
@@ -72,14 +72,21 @@ impl Plugin for PlayerMirror {
         _ = engine.register_concommand(
             "client_connect",
             client_connect,
-            "makes a connection to the address as a client",
+            "makes a connection to the address as a client, usage : client_connect <address> [name=<name>] [timeout=<seconds>] [tick=<rate like 30hz>]",
             sponly | server,
         );
 
         _ = engine.register_concommand(
             "server_setup",
             server_setup,
-            "sets up a server on the specified address",
+            "sets up a server on the specified address, usage : server_setup <address> [name=<name>] [transport=<tcp|udp>] [players=<max players>] [tick=<rate like 30hz>]",
+            sponly | server,
+        );
+
+        _ = engine.register_concommand(
+            "mirror_extrapolation",
+            mirror_extrapolation,
+            "sets how long ghosts keep moving when updates are late and how long they take to get back on track, usage : mirror_extrapolation <max seconds> [blend seconds]",
             sponly | server,
         );
     }
@@ -131,6 +138,40 @@ fn client_connect(command: CCommandResult) {
             return;
         }
     };
+    let args = match keyed_args(&command.args[1..], &["name", "timeout", "tick"]) {
+        Ok(args) => args,
+        Err(err) => {
+            log::error!("{err}");
+            return;
+        }
+    };
+
+    let name = args
+        .get("name")
+        .map(|name| name.to_string())
+        .unwrap_or_default();
+    let timeout = match args.get("timeout") {
+        Some(arg) => match arg
+            .parse()
+            .ok()
+            .and_then(|seconds| Duration::try_from_secs_f32(seconds).ok())
+        {
+            Some(timeout) if !timeout.is_zero() => Some(timeout),
+            _ => {
+                log::error!("the timeout has to be a positive amount of seconds");
+                return;
+            }
+        },
+        None => None,
+    };
+    let tick_rate = match args.get("tick").map(|arg| arg.parse::<TickRate>()) {
+        Some(Ok(tick_rate)) => Some(tick_rate),
+        Some(Err(err)) => {
+            log::error!("{err}");
+            return;
+        }
+        None => None,
+    };
 
     let mut mirrortype = match PLUGIN.wait().mirrortype.wait().try_write() {
         Ok(mirrortype) => mirrortype,
@@ -140,35 +181,29 @@ fn client_connect(command: CCommandResult) {
         }
     };
 
-    match &mut *mirrortype {
-        MirroringType::Server(s) => {
-            s.shutdown();
+    if let MirroringType::Server(_) = &*mirrortype {
+        log::info!("stoping server");
+    }
 
-            log::info!("stoping server");
-            log::info!("connecting to server");
+    let client = mirrortype.client();
 
-            let mut client = PlayerMirrorClient::new();
+    if let Err(err) = client.set_name(&name) {
+        log::warn!("couldn't set name : {err}")
+    }
 
-            match client.connect(address) {
-                Ok(_) => {
-                    log::info!("connected to server")
-                }
-                Err(err) => {
-                    log::error!("failed to connect : {err}");
-                    return;
-                }
-            }
+    if let Some(timeout) = timeout {
+        client.set_connect_timeout(timeout);
+    }
 
-            *mirrortype = MirroringType::Client(client);
-        }
-        MirroringType::Client(c) => match c.connect(address) {
-            Ok(_) => {
-                log::info!("connected to server")
-            }
-            Err(err) => {
-                log::error!("failed to connect : {err}")
-            }
-        },
+    if let Some(tick_rate) = tick_rate {
+        client.set_tick_rate(tick_rate);
+    }
+
+    log::info!("connecting to server");
+
+    // the worker connects in the background, MirrorGetStatus tells how it went
+    if let Err(err) = client.connect(address) {
+        log::error!("failed to connect : {err}")
     }
 }
 
@@ -181,6 +216,46 @@ fn server_setup(command: CCommandResult) {
             return;
         }
     };
+    let args = match keyed_args(
+        &command.args[1..],
+        &["name", "transport", "players", "tick"],
+    ) {
+        Ok(args) => args,
+        Err(err) => {
+            log::error!("{err}");
+            return;
+        }
+    };
+
+    // an empty name keeps the default
+    let name = args.get("name").filter(|name| !name.is_empty());
+    let transport = match args
+        .get("transport")
+        .map(|arg| arg.parse::<PositionTransport>())
+    {
+        Some(Ok(transport)) => transport,
+        Some(Err(err)) => {
+            log::error!("{err}");
+            return;
+        }
+        None => PositionTransport::default(),
+    };
+    let max_players = match args.get("players").map(|arg| arg.parse::<usize>()) {
+        Some(Ok(max_players)) => Some(max_players),
+        Some(Err(_)) => {
+            log::error!("the player cap has to be a number");
+            return;
+        }
+        None => None,
+    };
+    let tick_rate = match args.get("tick").map(|arg| arg.parse::<TickRate>()) {
+        Some(Ok(tick_rate)) => Some(tick_rate),
+        Some(Err(err)) => {
+            log::error!("{err}");
+            return;
+        }
+        None => None,
+    };
 
     let mut mirrortype = match PLUGIN.wait().mirrortype.wait().try_write() {
         Ok(mirrortype) => mirrortype,
@@ -190,32 +265,102 @@ fn server_setup(command: CCommandResult) {
         }
     };
 
-    match &mut *mirrortype {
-        MirroringType::Server(s) => match s.bind(address) {
-            Ok(_) => log::info!("started new server"),
+    if let MirroringType::Client(_) = &*mirrortype {
+        log::info!("stopping connection");
+    }
 
-            Err(err) => {
-                log::error!("failed to bind to address : {err}")
-            }
-        },
-        MirroringType::Client(c) => {
-            c.shutdown();
+    let server = mirrortype.server();
+    server.set_position_transport(transport);
 
-            log::info!("stopping connection");
-            log::info!("starting new server");
+    if let Some(name) = name {
+        if let Err(err) = server.set_name(name) {
+            log::warn!("couldn't set host name : {err}")
+        }
+    }
 
-            let mut server = PlayerMirrorServer::new();
+    // set before binding so nobody gets in past the cap
+    if let Some(max_players) = max_players {
+        if let Err(err) = server.set_max_players(max_players) {
+            log::error!("couldn't set max players : {err}");
+            return;
+        }
+    }
 
-            match server.bind(address) {
-                Ok(_) => {}
-                Err(err) => {
-                    log::error!("failed to bind to address : {err}");
-                    return;
-                }
-            }
+    if let Some(tick_rate) = tick_rate {
+        if let Err(err) = server.set_tick_rate(tick_rate) {
+            log::warn!("couldn't set tick rate : {err}")
+        }
+    }
+
+    match server.bind(address) {
+        Ok(_) => log::info!("started new server"),
+        Err(err) => log::error!("failed to bind to address : {err}"),
+    }
+}
+
+/// splits the `key=value` arguments that come after the address, any of them can be left out
+fn keyed_args<'a>(args: &'a [String], keys: &[&str]) -> Result<HashMap<&'a str, &'a str>, String> {
+    let mut values = HashMap::new();
+
+    for arg in args {
+        let Some((key, value)) = arg.split_once('=') else {
+            return Err(format!("{arg} should look like {}=<value>", keys.join("|")));
+        };
 
-            *mirrortype = MirroringType::Server(server)
+        if !keys.contains(&key) {
+            return Err(format!(
+                "{key} isn't a setting, expected one of {}",
+                keys.join(", ")
+            ));
         }
+
+        values.insert(key, value);
+    }
+
+    Ok(values)
+}
+
+#[rrplug::concommand]
+fn mirror_extrapolation(command: CCommandResult) {
+    let seconds = |arg: &String| {
+        arg.parse()
+            .ok()
+            .and_then(|seconds| Duration::try_from_secs_f32(seconds).ok())
+    };
+
+    let mut extrapolation = Extrapolation::default();
+    match command.args.get(0).map(seconds) {
+        Some(Some(max)) => extrapolation.max = max,
+        _ => {
+            log::error!("the max has to be a positive amount of seconds, 0 turns it off");
+            return;
+        }
+    }
+    match command.args.get(1).map(seconds) {
+        Some(Some(blend)) => extrapolation.blend = blend,
+        Some(None) => {
+            log::error!("the blend has to be an amount of seconds, 0 snaps ghosts back right away");
+            return;
+        }
+        None => {}
+    }
+
+    let mirrortype = match PLUGIN.wait().mirrortype.wait().try_read() {
+        Ok(mirrortype) => mirrortype,
+        Err(err) => {
+            log::error!("{err:?}");
+            return;
+        }
+    };
+
+    // sticks around until client_connect or server_setup switch modes
+    let set = match &*mirrortype {
+        MirroringType::Client(client) => client.set_extrapolation(extrapolation),
+        MirroringType::Server(server) => server.set_extrapolation(extrapolation),
+    };
+
+    if let Err(err) = set {
+        log::error!("couldn't set extrapolation : {err}");
     }
 }
 
@@ -228,12 +373,25 @@ fn wait_for_full_startup() {
     sq_return_null!()
 }
 
+#[rrplug::sqfunction(VM=Server,ExportName=MirrorGetStatus)]
+fn get_status() -> String {
+    // a concommand could be holding the lock while it connects or binds
+    let status = match PLUGIN.wait().mirrortype.wait().try_read() {
+        Ok(mirrortype) => mirrortype.status(),
+        Err(_) => "busy".to_string(),
+    };
+
+    sq_return_string!(status, sqvm, sq_functions)
+}
+
 #[rrplug::sqfunction(VM=Server,ExportName=MirrorPlayerRunFrame)]
 fn runframe(
     player_pos: Vector3,
     player_viewangle: Vector3,
+    player_velocity: Vector3,
     action: i32,
-    func_move_dummies: fn(i32, Vector3, Vector3, i32),
+    func_move_dummies: fn(i32, Vector3, Vector3, i32, String),
+    func_hide_dummy: fn(i32),
 ) {
     let mut mirrortype = match PLUGIN.wait().mirrortype.wait().try_write() {
         Ok(mirrortype) => mirrortype,
@@ -245,80 +403,88 @@ fn runframe(
 
     match &mut *mirrortype {
         MirroringType::Server(s) => {
-            if s.is_listening() {
-                let player_positions = s.get_positions_from_streams();
-
-                if let Ok(player_positions) = player_positions {
-                    let zero = Vector3::from([0., 0., 0.]);
-
-                    for (index, info) in player_positions
-                        .to_vec()
-                        .iter()
-                        .filter(|v| v.get_position() != zero) // since we don't clear positions this should be ok
-                        .enumerate()
-                    {
-                        let index = index as i32;
-                        if let Err(err) = call_sq_object_function!(
-                            sqvm,
-                            sq_functions,
-                            func_move_dummies,
-                            index,
-                            info.get_position(),
-                            info.get_viewangle(),
-                            info.action.clone() as i32
-                        ) {
-                            err.log()
-                        }
+            // runs even when not listening so the ghosts of kicked players get hidden
+            let player_positions = s.get_positions_from_streams();
+
+            for index in s.take_left_ghosts() {
+                if let Err(err) =
+                    call_sq_object_function!(sqvm, sq_functions, func_hide_dummy, index)
+                {
+                    err.log()
+                }
+            }
+
+            if let Ok(player_positions) = player_positions {
+                let zero = Vector3::from([0., 0., 0.]);
+
+                for ghost in player_positions
+                    .iter()
+                    .filter(|ghost| ghost.info.get_position() != zero) // hasn't sent anything yet
+                {
+                    if let Err(err) = call_sq_object_function!(
+                        sqvm,
+                        sq_functions,
+                        func_move_dummies,
+                        ghost.index,
+                        ghost.info.get_position(),
+                        ghost.info.get_viewangle(),
+                        i32::from(ghost.info.action.clone()),
+                        ghost.name.clone()
+                    ) {
+                        err.log()
                     }
-                };
+                }
+            };
 
+            if s.is_listening() {
                 _ = s.push_position_to_streams(PlayerInfo::new(
                     player_pos,
                     player_viewangle,
-                    action.try_into().unwrap(),
+                    player_velocity,
+                    Action::or_unknown(action),
                 ));
-
-                _ = s.accept_connection(); // spams too many useless errors >:(
             }
         }
         MirroringType::Client(c) => {
-            if c.is_connected() {
-                let player_positons = c.get_other_positions();
+            // runs even when disconnected so the ghosts of a lost connection get hidden
+            let player_positons = c.get_other_positions();
 
+            for index in c.take_left_ghosts() {
+                if let Err(err) =
+                    call_sq_object_function!(sqvm, sq_functions, func_hide_dummy, index)
+                {
+                    err.log()
+                }
+            }
+
+            if let Ok(player_positons) = player_positons {
                 let zero = Vector3::from([0., 0., 0.]);
 
-                for (index, info) in player_positons
-                    .to_vec()
+                for ghost in player_positons
                     .iter()
-                    .filter(|v| v.get_position() != zero)
-                    .enumerate()
+                    .filter(|ghost| ghost.info.get_position() != zero)
                 {
-                    let sent_player_pos = info.get_position();
-                    let sent_player_viewangle = info.get_viewangle();
-                    let sent_action = info.action.clone() as i32;
-
-                    if sent_player_pos == player_pos {
-                        continue;
-                    }
-
-                    let index = index as i32;
                     if let Err(err) = call_sq_object_function!(
                         sqvm,
                         sq_functions,
                         func_move_dummies,
-                        index,
-                        sent_player_pos,
-                        sent_player_viewangle,
-                        sent_action
+                        ghost.index,
+                        ghost.info.get_position(),
+                        ghost.info.get_viewangle(),
+                        i32::from(ghost.info.action.clone()),
+                        ghost.name.clone()
                     ) {
                         err.log()
                     }
                 }
+            }
 
+            if c.is_connected() {
                 if let Err(err) = c.push_position(PlayerInfo::new(
                     player_pos,
                     player_viewangle,
-                    action.try_into().unwrap(),
+                    player_velocity,
+                    Action::or_unknown(action),
                 )) {
                     log::warn!("{err}");
                 }
@@ -1,254 +1,1605 @@
-use crate::shared::{PlayerInfo, PlayerInfoArray, WorkerMessage, SINGLE_PACKET_SIZE};
-use rrplug::{log, prelude::wait};
+use crate::shared::{
+    is_newer, is_timeout, join_timeout, sanitize_name, Capabilities, Connection, ConnectionExt,
+    Datagram, Extrapolation, FramedStream, Ghost, GhostSlots, Heartbeat, Interpolation, Message,
+    PlayerId, PlayerInfo, PositionTransport, RejectReason, ServerHandshake, Snapshot,
+    SnapshotHistory, Stamper, TickRate, Timeouts, DEFAULT_MAX_PLAYERS, HANDSHAKE_TIMEOUT, HOST_ID,
+    MAX_DATAGRAM_SIZE, SHUTDOWN_TIMEOUT,
+};
+use rrplug::log;
 use std::{
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
-    ops::Deref,
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Display},
+    io,
+    net::{IpAddr, SocketAddr, TcpListener, UdpSocket},
     sync::{
-        mpsc::{self, Receiver, Sender},
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender, TryRecvError},
         Arc, Mutex, RwLock,
     },
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
+/// how long the event loop sleeps when none of its sockets had anything to do
+const IDLE_WAIT: Duration = Duration::from_millis(2);
+/// how often the acceptor checks for new connections and whether it should stop
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(10);
+/// what clients are told when the server shuts down
+const SERVER_CLOSING: &str = "server closing";
+/// how much of what a peer still sent is read before closing its connection
+const MAX_DRAINED_FRAMES: usize = 64;
+
+/// where the server is at, see `PlayerMirrorServer::accept_status` for how accepting goes
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ServerState {
+    #[default]
+    Idle,
+    Listening,
+    /// the last bind didn't work out
+    Failed(String),
+}
+
+impl Display for ServerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Idle => write!(f, "idle"),
+            Self::Listening => write!(f, "listening"),
+            Self::Failed(reason) => write!(f, "failed : {reason}"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct PlayerMirrorServer {
-    pub player_positions: Arc<RwLock<PlayerInfoArray>>, // max 16 players
-    listener: Option<TcpListener>,
-    workers: Vec<ConnectionWorker>,
-    sender: Mutex<Sender<WorkerMessage>>,
+    pub players: Arc<RwLock<PlayerTable>>,
+    state: ServerState,
+    event_loop: EventLoop,
+    acceptor: Option<Acceptor>,
+    accept_status: Arc<Mutex<AcceptStatus>>,
+    ghosts: GhostSlots,
+    transport: PositionTransport,
+    stamper: Stamper,
 }
 
 impl PlayerMirrorServer {
     pub fn new() -> Self {
-        let info = PlayerInfo::default();
+        Self::with_timeouts(Timeouts::default())
+    }
 
-        let positions = (0..16)
-            .map(|_| info.clone())
-            .collect::<Vec<PlayerInfo>>()
-            .try_into()
-            .unwrap();
+    pub fn with_timeouts(timeouts: Timeouts) -> Self {
+        let players = Arc::new(RwLock::new(PlayerTable::new()));
+
+        Self {
+            event_loop: EventLoop::new(players.clone(), timeouts),
+            players,
+            state: ServerState::Idle,
+            acceptor: None,
+            accept_status: Arc::new(Mutex::new(AcceptStatus::default())),
+            ghosts: GhostSlots::new(),
+            transport: PositionTransport::default(),
+            stamper: Stamper::new(),
+        }
+    }
 
-        let positions = Arc::new(RwLock::new(positions));
+    pub fn bind(&mut self, address: String) -> Result<(), String> {
+        self.shutdown();
 
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+        let bound = self.listen(address);
+        self.state = match &bound {
+            Ok(_) => ServerState::Listening,
+            Err(err) => ServerState::Failed(err.clone()),
+        };
+        bound
+    }
 
-        // set size of 15
-        const SIZE: usize = 15;
+    fn listen(&mut self, address: String) -> Result<(), String> {
+        let listener = TcpListener::bind(address).map_err(|err| err.to_string())?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|err| format!("cannot set non blocking : {err}"))?;
 
-        let mut workers = Vec::with_capacity(SIZE);
-        for id in 0..(SIZE - 1) {
-            workers.push(ConnectionWorker::new(
-                id,
-                receiver.clone(),
-                positions.clone(),
-            ))
-        }
+        let local_addr = listener.local_addr().map_err(|err| err.to_string())?;
 
-        Self {
-            player_positions: positions,
-            listener: None,
-            workers,
-            sender: Mutex::new(sender),
-        }
+        let datagrams = match self.transport {
+            // same port as the listener so clients know where to send their datagrams
+            PositionTransport::Udp => Some(
+                UdpSocket::bind(local_addr)
+                    .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+                    .map_err(|err| format!("couldn't bind udp socket : {err}"))?,
+            ),
+            PositionTransport::Tcp => None,
+        };
+
+        self.event_loop.send(Command::Listen {
+            datagrams,
+            offered: self.transport.capabilities(),
+        })?;
+
+        *self
+            .accept_status
+            .lock()
+            .or(Err("can't have locks in ohio"))? = AcceptStatus::default();
+
+        self.acceptor = Some(Acceptor::new(
+            listener,
+            local_addr,
+            self.event_loop.commands()?,
+            self.accept_status.clone(),
+        ));
+
+        Ok(())
     }
 
-    pub fn bind(&mut self, address: String) -> Result<(), String> {
-        if let Some(l) = self.listener.take() {
-            drop(l);
-        }
+    /// only takes effect on the next `bind`
+    pub fn set_position_transport(&mut self, transport: PositionTransport) {
+        self.transport = transport
+    }
 
-        match TcpListener::bind(address) {
-            Ok(l) => {
-                l.set_nonblocking(true).expect("cannot set non blocking"); // just so I wouldn't need to spin up another thread
-                self.listener = Some(l);
-                Ok(())
-            }
-            Err(err) => Err(err.to_string()),
-        }
+    pub fn state(&self) -> ServerState {
+        self.state.clone()
     }
 
     pub fn is_listening(&self) -> bool {
-        self.listener.is_some()
+        self.acceptor.is_some()
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        Some(self.acceptor.as_ref()?.local_addr)
+    }
+
+    /// how accepting connections went since the last `bind`
+    pub fn accept_status(&self) -> AcceptStatus {
+        self.accept_status
+            .lock()
+            .map(|status| status.clone())
+            .unwrap_or_default()
     }
 
+    /// stops accepting and closes every connection, the server can be bound again afterwards
     pub fn shutdown(&mut self) {
-        if let Some(l) = self.listener.take() {
-            drop(l);
-        }
+        self.state = ServerState::Idle;
+        self.acceptor = None;
+        _ = self.event_loop.send(Command::Shutdown);
     }
 
-    pub fn accept_connection(&mut self) -> Result<(), String> {
-        for conn in self
-            .listener
-            .as_ref()
-            .expect("someone forgot to handle an option")
-            .incoming()
-        {
-            match conn {
-                Ok(conn) => {
-                    _ = self.sender.lock().unwrap().send(WorkerMessage::Work(conn));
-                }
-                Err(err) => return Err(err.to_string()),
-            }
+    /// how often snapshots go out to every player
+    pub fn set_tick_rate(&self, tick_rate: TickRate) -> Result<(), String> {
+        self.event_loop.send(Command::TickRate(tick_rate))
+    }
+
+    /// starts serving a connection that's already open, it gets turned away if the server is full
+    pub fn accept(&self, connection: Box<dyn Connection>) -> Result<(), String> {
+        self.event_loop.send(Command::Accept(connection))
+    }
+
+    /// how many players can be on the server at once, the host included
+    ///
+    /// players that are already connected stay even if there are more of them
+    pub fn set_max_players(&self, max_players: usize) -> Result<(), &'static str> {
+        if max_players == 0 {
+            return Err("the host needs a spot too");
         }
+
+        self.players
+            .write()
+            .or(Err("can't have locks in ohio"))?
+            .max_players = max_players;
+
         Ok(())
     }
 
-    pub fn get_positions_from_streams(&mut self) -> Result<PlayerInfoArray, &'static str> {
-        let lock = self
-            .player_positions
+    /// every connected player except the host, each bound to the same dummy for as long as they stay
+    ///
+    /// positions are blended between ticks so this should be called every frame
+    pub fn get_positions_from_streams(&mut self) -> Result<Vec<Ghost>, &'static str> {
+        let mut players = self.players.write().or(Err("can't have locks in ohio"))?;
+        let positions = players.ghost_positions();
+
+        Ok(self.ghosts.ghosts(positions, |id| players.name_of(id)))
+    }
+
+    /// dummies of players that left since the last call, these should be hidden
+    pub fn take_left_ghosts(&mut self) -> Vec<i32> {
+        self.ghosts.take_released()
+    }
+
+    /// every dummy that's showing or still has to be hidden, used when switching modes
+    pub fn release_ghosts(&mut self) -> Vec<i32> {
+        self.ghosts.release_all()
+    }
+
+    /// dummies from somewhere else that get hidden with the next `take_left_ghosts`
+    pub fn hide_ghosts(&mut self, indexes: Vec<i32>) {
+        self.ghosts.hide(indexes)
+    }
+
+    /// how far ghosts keep going when a player's positions are late
+    pub fn set_extrapolation(&self, extrapolation: Extrapolation) -> Result<(), &'static str> {
+        self.players
             .write()
-            .or(Err("can't have locks in ohio"))?;
+            .or(Err("can't have locks in ohio"))?
+            .set_extrapolation(extrapolation);
 
-        let mut positions = lock.deref().clone();
+        Ok(())
+    }
 
-        positions[15] = PlayerInfo::default(); // this is the local player on the server
+    /// the name clients see above the host's ghost
+    pub fn set_name(&self, name: &str) -> Result<(), &'static str> {
+        let name = sanitize_name(name).ok_or("that name is empty")?;
 
-        Ok(positions)
+        self.players
+            .write()
+            .or(Err("can't have locks in ohio"))?
+            .rename(HOST_ID, name);
+
+        Ok(())
     }
 
     pub fn push_position_to_streams(&self, info: PlayerInfo) -> Result<(), &'static str> {
-        let mut lock = self
-            .player_positions
+        self.players
             .write()
-            .or(Err("can't have locks in ohio"))?;
-        *lock.get_mut(15).unwrap() = info; // ^ or try_write?
+            .or(Err("can't have locks in ohio"))?
+            .update(HOST_ID, self.stamper.stamp(info)); // ^ or try_write?
 
         Ok(())
     }
 }
 
-impl Drop for PlayerMirrorServer {
-    fn drop(&mut self) {
-        let lock_poision = self.player_positions.clone();
+#[derive(Debug, Clone)]
+pub struct PlayerEntry {
+    pub name: String,
+    pub info: PlayerInfo,
+    /// datagrams claiming to be this player are only taken from here
+    pub peer_ip: Option<IpAddr>,
+    /// last `PlayerInfo::sequence` applied, older ones get dropped
+    pub sequence: Option<u32>,
+    /// where this player's positions last came in over udp, their snapshots go back there
+    pub datagram_addr: Option<SocketAddr>,
+    /// the connection this player is on, `None` for the host
+    pub connection: Option<usize>,
+}
 
-        thread::spawn(move || {
-            #[allow(unused_variables)]
-            let lock = lock_poision.write().unwrap();
-            panic!();
-        }); // this will poision the lock making it invalid and forcing thread to stop
+/// every player on the server keyed by the id they got during the handshake
+#[derive(Debug)]
+pub struct PlayerTable {
+    players: BTreeMap<PlayerId, PlayerEntry>,
+    next_id: PlayerId,
+    pub max_players: usize,
+    /// where everyone but the host was on the last few ticks, the host's ghosts are drawn from this
+    interpolation: Interpolation,
+}
 
-        let lock = self.sender.lock().unwrap();
+impl PlayerTable {
+    pub fn new() -> Self {
+        Self {
+            players: BTreeMap::from([(
+                HOST_ID,
+                PlayerEntry {
+                    name: "host".to_string(),
+                    info: PlayerInfo::default(),
+                    peer_ip: None,
+                    sequence: None,
+                    datagram_addr: None,
+                    connection: None,
+                },
+            )]),
+            next_id: HOST_ID + 1,
+            max_players: DEFAULT_MAX_PLAYERS,
+            interpolation: Interpolation::new(),
+        }
+    }
 
-        for worker in self.workers.iter() {
-            if worker.thread.is_some() {
-                _ = lock.send(WorkerMessage::Death);
+    /// a player coming back from the same address gets `previous_id` again if it's still around
+    pub fn join(
+        &mut self,
+        name: Option<String>,
+        peer_ip: Option<IpAddr>,
+        previous_id: Option<PlayerId>,
+        connection: usize,
+    ) -> Result<PlayerId, RejectReason> {
+        if let Some(id) = previous_id.filter(|id| *id != HOST_ID) {
+            if let Some(entry) = self
+                .players
+                .get_mut(&id)
+                .filter(|entry| entry.peer_ip == peer_ip)
+            {
+                // the old connection notices it lost the player on its next poll
+                entry.connection = Some(connection);
+                entry.sequence = None;
+                entry.datagram_addr = None;
+                if let Some(name) = name {
+                    entry.name = name;
+                }
+                return Ok(id);
             }
         }
+
+        if self.players.len() >= self.max_players {
+            return Err(RejectReason::ServerFull {
+                max_players: self.max_players as u32,
+            });
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.players.insert(
+            id,
+            PlayerEntry {
+                name: name.unwrap_or_else(|| format!("player {id}")),
+                info: PlayerInfo::default(),
+                peer_ip,
+                sequence: None,
+                datagram_addr: None,
+                connection: Some(connection),
+            },
+        );
+
+        Ok(id)
+    }
+
+    pub fn rename(&mut self, id: PlayerId, name: String) {
+        if let Some(entry) = self.players.get_mut(&id) {
+            entry.name = name;
+        }
+    }
+
+    pub fn name_of(&self, id: PlayerId) -> String {
+        self.players
+            .get(&id)
+            .map(|entry| entry.name.clone())
+            .unwrap_or_else(|| format!("player {id}"))
+    }
+
+    pub fn leave(&mut self, id: PlayerId) -> Option<PlayerEntry> {
+        self.interpolation.remove(id);
+        self.players.remove(&id)
+    }
+
+    /// the connection `id` is on, see `join`
+    pub fn connection_of(&self, id: PlayerId) -> Option<usize> {
+        self.players.get(&id).and_then(|entry| entry.connection)
+    }
+
+    /// drops everyone but the host and forgets where the host was
+    pub fn clear(&mut self) {
+        self.players.retain(|id, _| *id == HOST_ID);
+        self.interpolation.clear();
+
+        if let Some(host) = self.players.get_mut(&HOST_ID) {
+            host.info = PlayerInfo::default();
+            host.sequence = None;
+        }
+    }
+
+    /// applies a position unless it's stale, returns `false` if it was dropped
+    pub fn update(&mut self, id: PlayerId, info: PlayerInfo) -> bool {
+        self.players
+            .get_mut(&id)
+            .is_some_and(|entry| Self::apply(entry, info))
+    }
+
+    /// applies a position that came in over udp, returns `false` if it was dropped
+    pub fn update_from_datagram(
+        &mut self,
+        id: PlayerId,
+        from: SocketAddr,
+        info: PlayerInfo,
+    ) -> bool {
+        let Some(entry) = self
+            .players
+            .get_mut(&id)
+            .filter(|entry| entry.peer_ip == Some(from.ip()))
+        else {
+            return false;
+        };
+
+        if !Self::apply(entry, info) {
+            return false;
+        }
+
+        entry.datagram_addr = Some(from);
+        true
+    }
+
+    fn apply(entry: &mut PlayerEntry, info: PlayerInfo) -> bool {
+        if matches!(entry.sequence, Some(last) if !is_newer(info.sequence, last)) {
+            return false;
+        }
+
+        entry.sequence = Some(info.sequence);
+        entry.info = info;
+        true
+    }
+
+    pub fn datagram_addr(&self, id: PlayerId) -> Option<SocketAddr> {
+        self.players.get(&id).and_then(|entry| entry.datagram_addr)
+    }
+
+    /// remembers where everyone but the host is at `time` on the server's clock, see `ghost_positions`
+    pub fn record_tick(&mut self, time: Duration) {
+        let others = self.snapshot(Some(HOST_ID));
+        self.interpolation.push(time, &others, Instant::now());
+    }
+
+    /// everyone but the host blended between the last few ticks
+    pub fn ghost_positions(&mut self) -> Vec<(PlayerId, PlayerInfo)> {
+        self.interpolation.sample(Instant::now())
     }
+
+    pub fn set_extrapolation(&mut self, extrapolation: Extrapolation) {
+        self.interpolation.set_extrapolation(extrapolation)
+    }
+
+    /// every player but `except`
+    pub fn snapshot(&self, except: Option<PlayerId>) -> Vec<(PlayerId, PlayerInfo)> {
+        self.players
+            .iter()
+            .filter(|(id, _)| Some(**id) != except)
+            .map(|(id, entry)| (*id, entry.info.clone()))
+            .collect()
+    }
+
+    pub fn roster(&self) -> impl Iterator<Item = (PlayerId, &str)> {
+        self.players
+            .iter()
+            .map(|(id, entry)| (*id, entry.name.as_str()))
+    }
+
+    /// joins and leaves `receiver` hasn't been told about yet, a rename is sent as another join
+    pub fn roster_changes(
+        &self,
+        receiver: PlayerId,
+        known: &mut BTreeMap<PlayerId, String>,
+    ) -> Vec<Message> {
+        let mut changes = Vec::new();
+
+        known.retain(|id, _| {
+            let stayed = self.players.contains_key(id);
+            if !stayed {
+                changes.push(Message::Leave(*id));
+            }
+            stayed
+        });
+
+        for (id, name) in self.roster().filter(|(id, _)| *id != receiver) {
+            if known.get(&id).map(String::as_str) != Some(name) {
+                known.insert(id, name.to_string());
+                changes.push(Message::Join {
+                    id,
+                    name: name.to_string(),
+                });
+            }
+        }
+
+        changes
+    }
+}
+
+impl Default for PlayerTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// what the server tells its event loop
+enum Command {
+    Listen {
+        datagrams: Option<UdpSocket>,
+        offered: Capabilities,
+    },
+    /// disconnects everyone
+    Shutdown,
+    Accept(Box<dyn Connection>),
+    TickRate(TickRate),
+}
+
+/// runs the server on one thread that owns every connection
+///
+/// nothing on that thread blocks so idle players don't cost anything
+#[derive(Debug)]
+struct EventLoop {
+    thread: Option<JoinHandle<()>>,
+    running: Arc<AtomicBool>,
+    commands: Mutex<Sender<Command>>,
+}
+
+impl EventLoop {
+    fn new(players: Arc<RwLock<PlayerTable>>, timeouts: Timeouts) -> Self {
+        let (commands, receiver) = mpsc::channel();
+        let running = Arc::new(AtomicBool::new(true));
+        let flag = running.clone();
+
+        Self {
+            thread: Some(thread::spawn(move || {
+                ServerCore::new(players, timeouts).run(receiver, flag)
+            })),
+            running,
+            commands: Mutex::new(commands),
+        }
+    }
+
+    fn send(&self, command: Command) -> Result<(), String> {
+        self.commands()?
+            .send(command)
+            .or(Err("the event loop is gone".to_string()))
+    }
+
+    fn commands(&self) -> Result<Sender<Command>, String> {
+        Ok(self
+            .commands
+            .lock()
+            .or(Err("can't have locks in ohio"))?
+            .clone())
+    }
+}
+
+impl Drop for EventLoop {
+    fn drop(&mut self) {
+        log::warn!("Shutting down server event loop");
+
+        self.running.store(false, Ordering::Relaxed);
+
+        if let Some(Err(_)) = self
+            .thread
+            .take()
+            .map(|t| join_timeout(t, SHUTDOWN_TIMEOUT))
+        {
+            log::error!("server event loop didn't stop, leaving it behind");
+        }
+    }
+}
+
+/// how the acceptor is doing, accept failures that aren't just a missing connection end up here
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AcceptStatus {
+    pub accepted: u64,
+    pub failures: u64,
+    pub last_error: Option<String>,
 }
 
+/// accepts connections on its own thread and hands them to the event loop
 #[derive(Debug)]
-struct ConnectionWorker {
+struct Acceptor {
     thread: Option<JoinHandle<()>>,
-    id: usize,
+    running: Arc<AtomicBool>,
+    local_addr: SocketAddr,
 }
 
-impl ConnectionWorker {
+impl Acceptor {
     fn new(
-        id: usize,
-        jobs: Arc<Mutex<Receiver<WorkerMessage>>>,
-        positions: Arc<RwLock<PlayerInfoArray>>,
+        listener: TcpListener,
+        local_addr: SocketAddr,
+        commands: Sender<Command>,
+        status: Arc<Mutex<AcceptStatus>>,
     ) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let flag = running.clone();
+
         Self {
             thread: Some(thread::spawn(move || {
-                Self::job_handler(id, jobs, positions)
+                Self::run(listener, commands, status, flag)
             })),
-            id,
+            running,
+            local_addr,
         }
     }
 
-    fn job_handler(
-        id: usize,
-        jobs: Arc<Mutex<Receiver<WorkerMessage>>>,
-        positions: Arc<RwLock<PlayerInfoArray>>,
+    fn run(
+        listener: TcpListener,
+        commands: Sender<Command>,
+        status: Arc<Mutex<AcceptStatus>>,
+        running: Arc<AtomicBool>,
     ) {
-        loop {
-            let message = jobs.lock().unwrap().recv().unwrap(); // should never panic if it does
-                                                                // managing the error is needing or else the mutex might get poisoned
+        while running.load(Ordering::Relaxed) {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(err) if is_timeout(&err) => {
+                    thread::sleep(ACCEPT_INTERVAL);
+                    continue;
+                }
+                Err(err) => {
+                    log::warn!("couldn't accept connection : {err}");
 
-            let stream = match message {
-                WorkerMessage::Work(stream) => stream,
-                WorkerMessage::Death => break,
-                _ => continue,
+                    if let Ok(mut status) = status.lock() {
+                        status.failures += 1;
+                        status.last_error = Some(err.to_string());
+                    }
+
+                    thread::sleep(ACCEPT_INTERVAL);
+                    continue;
+                }
             };
 
-            log::info!("connection created for {id}");
+            if let Ok(mut status) = status.lock() {
+                status.accepted += 1;
+            }
 
-            match stream.set_nonblocking(false) {
-                Ok(_) => log::info!("{id} is blocking"),
-                Err(err) => {
-                    log::error!("{id} is non blocking {err}");
-                    continue;
+            if commands
+                .send(Command::Accept(Box::new(FramedStream::new(stream))))
+                .is_err()
+            {
+                log::error!("the event loop is gone, no longer accepting connections");
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for Acceptor {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+
+        if let Some(Err(_)) = self
+            .thread
+            .take()
+            .map(|t| join_timeout(t, SHUTDOWN_TIMEOUT))
+        {
+            log::error!("acceptor didn't stop, leaving it behind");
+        }
+    }
+}
+
+/// everything the event loop owns
+struct ServerCore {
+    players: Arc<RwLock<PlayerTable>>,
+    timeouts: Timeouts,
+    datagrams: Option<UdpSocket>,
+    /// read into by `receive_datagrams`, kept around so it isn't allocated on every pass
+    datagram_buffer: Vec<u8>,
+    offered: Capabilities,
+    peers: Vec<Peer>,
+    next_peer: usize,
+    /// the last snapshot that went out
+    tick: u32,
+    started: Instant,
+    history: SnapshotHistory,
+    tick_rate: TickRate,
+    next_tick: Instant,
+}
+
+impl ServerCore {
+    fn new(players: Arc<RwLock<PlayerTable>>, timeouts: Timeouts) -> Self {
+        Self {
+            players,
+            timeouts,
+            datagrams: None,
+            datagram_buffer: vec![0; MAX_DATAGRAM_SIZE],
+            offered: PositionTransport::default().capabilities(),
+            peers: Vec::new(),
+            next_peer: 0,
+            tick: 0,
+            started: Instant::now(),
+            history: SnapshotHistory::new(),
+            tick_rate: TickRate::default(),
+            next_tick: Instant::now(),
+        }
+    }
+
+    fn run(mut self, commands: Receiver<Command>, running: Arc<AtomicBool>) {
+        while running.load(Ordering::Relaxed) {
+            let mut busy = false;
+
+            loop {
+                match commands.try_recv() {
+                    Ok(command) => self.handle_command(command),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return,
                 }
+                busy = true;
             }
 
-            Self::work(id, stream, &positions);
+            busy |= self.receive_datagrams();
+            busy |= self.poll_peers();
+
+            if Instant::now() >= self.next_tick {
+                self.next_tick = self.tick_rate.next_tick(self.next_tick);
+                self.broadcast();
+            }
 
-            log::error!("connection terminated for {id}");
+            if !busy {
+                thread::sleep(IDLE_WAIT);
+            }
         }
 
-        log::warn!("{id} worker was told to stop");
+        self.disconnect_all(SERVER_CLOSING);
     }
 
-    fn work(id: usize, mut stream: TcpStream, positions: &Arc<RwLock<PlayerInfoArray>>) {
-        let zero = PlayerInfo::default();
-        let mut player_positions = Vec::with_capacity(16);
+    /// tells every connection why it's being closed and forgets about their players
+    fn disconnect_all(&mut self, reason: &str) {
+        // cleared first so nobody sees players whose connection is already gone
+        match self.players.write() {
+            Ok(mut players) => players.clear(),
+            Err(err) => log::error!("couldn't clear players : {err}"),
+        }
+
+        for mut peer in self.peers.drain(..) {
+            log::info!("closing connection {}", peer.number);
+            peer.disconnect(reason);
+        }
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Listen { datagrams, offered } => {
+                self.datagrams = datagrams;
+                self.offered = offered;
+            }
+            Command::Shutdown => {
+                self.datagrams = None;
+                self.disconnect_all(SERVER_CLOSING);
+            }
+            Command::Accept(connection) => self.add_peer(connection),
+            Command::TickRate(tick_rate) => {
+                self.tick_rate = tick_rate;
+                self.next_tick = Instant::now();
+            }
+        }
+    }
+
+    fn add_peer(&mut self, mut connection: Box<dyn Connection>) {
+        if let Err(err) = connection.set_nonblocking(true) {
+            log::error!("connection is blocking {err}");
+            return;
+        }
+
+        log::info!("connection created for {}", self.next_peer);
+
+        self.peers.push(Peer {
+            number: self.next_peer,
+            connection,
+            handshake: Some(ServerHandshake::new(self.offered)),
+            connected_at: Instant::now(),
+            id: None,
+            datagrams: false,
+            acked: None,
+            known_players: BTreeMap::new(),
+            heartbeat: Heartbeat::new(self.timeouts),
+        });
+        self.next_peer += 1;
+    }
+
+    /// applies the position datagrams that came in, the answer comes with the next tick
+    fn receive_datagrams(&mut self) -> bool {
+        let Some(socket) = self.datagrams.as_ref() else {
+            return false;
+        };
+
+        let buffer = &mut self.datagram_buffer;
+        let mut busy = false;
 
         loop {
-            let mut buffer = vec![0; SINGLE_PACKET_SIZE];
+            let (len, from) = match socket.recv_from(buffer) {
+                Ok(received) => received,
+                Err(err) if is_timeout(&err) => break,
+                // windows reports icmp port unreachable from earlier sends here, the next one is fine
+                Err(err) if err.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(err) => {
+                    // tried again next time around so a broken socket can't hold up the loop
+                    log::warn!("couldn't receive datagram : {err}");
+                    break;
+                }
+            };
+            busy = true;
 
-            _ = stream.read(&mut buffer);
+            let Ok(Datagram::Position { id, info }) = bincode::deserialize(&buffer[..len]) else {
+                continue;
+            };
 
-            let recvpacket: PlayerInfo = match bincode::deserialize(&buffer) {
-                Ok(p) => p,
+            match self.players.write() {
+                // late, duplicated and spoofed ones are dropped in there
+                Ok(mut players) => _ = players.update_from_datagram(id, from, info),
                 Err(err) => {
-                    log::error!("couldn't deserialize packet : {err}");
-                    return;
+                    log::error!("couldn't get lock : {err}");
+                    break;
                 }
+            }
+        }
+
+        busy
+    }
+
+    /// takes one snapshot of every player and sends it to everyone at once
+    ///
+    /// it goes out as a delta against the last snapshot each player acked, or in full when there's none to
+    /// go off of. players that send their positions over udp get it back that way
+    fn broadcast(&mut self) {
+        let mut players = match self.players.write() {
+            Ok(players) => players,
+            Err(err) => {
+                log::error!("couldn't get lock : {err}");
+                return;
+            }
+        };
+
+        self.tick = self.tick.wrapping_add(1);
+        let time = self.started.elapsed();
+
+        players.record_tick(time);
+        self.history.push(Snapshot {
+            tick: self.tick,
+            time,
+            players: players.snapshot(None),
+        });
+
+        // peers that acked the same snapshot get the same bytes
+        let mut encoded = HashMap::new();
+
+        for peer in self
+            .peers
+            .iter_mut()
+            .filter(|peer| peer.handshake.is_none())
+        {
+            let baseline = self.history.baseline(peer.acked);
+            let Some((frame, datagram)) = encoded
+                .entry(baseline)
+                .or_insert_with(|| encode_snapshot(&self.history, baseline))
+            else {
+                continue;
             };
 
-            {
-                let mut positions = match positions.write() {
-                    Ok(p) => p,
-                    Err(err) => {
-                        log::error!("couldn't get lock : {err}");
-                        return;
+            let datagram_addr = peer.id.and_then(|id| players.datagram_addr(id));
+
+            match (self.datagrams.as_ref(), datagram.as_ref(), datagram_addr) {
+                (Some(socket), Some(datagram), Some(addr)) if peer.datagrams => {
+                    if let Err(err) = socket.send_to(datagram, addr) {
+                        log::warn!("couldn't send datagram to {addr} : {err}");
                     }
-                };
+                }
+                // a connection that broke fails its next poll
+                _ => _ = peer.connection.send_frame(frame),
+            }
+        }
+    }
 
-                positions[id] = recvpacket;
+    fn poll_peers(&mut self) -> bool {
+        let mut busy = false;
+        let players = &self.players;
 
-                player_positions.extend_from_slice(&(*positions))
-            };
+        self.peers.retain_mut(|peer| match peer.poll(players) {
+            Ok(did_something) => {
+                busy |= did_something;
+                true
+            }
+            Err(reason) => {
+                log::warn!("connection terminated for {} : {reason}", peer.number);
+                peer.disconnect(&reason);
 
-            player_positions[id] = zero.clone();
+                if let Some(id) = peer.id {
+                    match players.write() {
+                        // a reconnect could have taken the player over already
+                        Ok(mut players) if players.connection_of(id) == Some(peer.number) => {
+                            _ = players.leave(id)
+                        }
+                        Ok(_) => {}
+                        Err(err) => log::error!("couldn't remove player {id} : {err}"),
+                    }
+                }
 
-            let sendpackets = match bincode::serialize(&player_positions) {
-                Ok(s) => s,
-                Err(err) => {
-                    log::error!("couldn't serialize packets : {err}");
-                    return;
+                busy = true;
+                false
+            }
+        });
+
+        busy
+    }
+}
+
+/// the newest snapshot as a delta against `baseline`, as a frame and as a datagram if it fits one
+fn encode_snapshot(
+    history: &SnapshotHistory,
+    baseline: Option<u32>,
+) -> Option<(Vec<u8>, Option<Vec<u8>>)> {
+    let delta = history.delta(baseline)?;
+
+    let frame = match bincode::serialize(&Message::Snapshot(delta.clone())) {
+        Ok(frame) => frame,
+        Err(err) => {
+            log::error!("couldn't serialize snapshot : {err}");
+            return None;
+        }
+    };
+    let datagram = match bincode::serialize(&Datagram::Snapshot(delta)) {
+        Ok(bytes) if bytes.len() <= MAX_DATAGRAM_SIZE => Some(bytes),
+        Ok(bytes) => {
+            log::warn!("snapshot of {} bytes doesn't fit a datagram", bytes.len());
+            None
+        }
+        Err(err) => {
+            log::error!("couldn't serialize snapshot : {err}");
+            None
+        }
+    };
+
+    Some((frame, datagram))
+}
+
+/// a connection owned by the event loop
+struct Peer {
+    /// tells connections apart, also in the player table
+    number: usize,
+    connection: Box<dyn Connection>,
+    /// `None` once the handshake is done
+    handshake: Option<ServerHandshake>,
+    connected_at: Instant,
+    id: Option<PlayerId>,
+    /// whether the client agreed to send its positions over udp
+    datagrams: bool,
+    /// the newest snapshot the client could rebuild, deltas are made against it
+    acked: Option<u32>,
+    /// names this client was already told about
+    known_players: BTreeMap<PlayerId, String>,
+    heartbeat: Heartbeat,
+}
+
+impl Peer {
+    /// handles everything that came in since the last call, fails once the connection should be closed
+    ///
+    /// returns whether anything happened
+    fn poll(&mut self, players: &Arc<RwLock<PlayerTable>>) -> Result<bool, String> {
+        let mut busy = false;
+
+        if self.handshake.is_some() {
+            busy |= self.poll_handshake(players)?;
+        }
+
+        if self.handshake.is_none() {
+            busy |= self.serve(players)?;
+        }
+
+        self.connection
+            .flush()
+            .map_err(|err| format!("couldn't send message : {err}"))?;
+
+        Ok(busy)
+    }
+
+    fn poll_handshake(&mut self, players: &Arc<RwLock<PlayerTable>>) -> Result<bool, String> {
+        let Some(handshake) = self.handshake.as_mut() else {
+            return Ok(false);
+        };
+
+        if self.connected_at.elapsed() > HANDSHAKE_TIMEOUT {
+            return Err("handshake timed out".to_string());
+        }
+
+        let peer_ip = self.connection.peer_addr().map(|addr| addr.ip());
+        let number = self.number;
+        let assigned = &mut self.id;
+
+        let session = handshake
+            .poll(self.connection.as_mut(), |login| {
+                let player_id = players
+                    .write()
+                    .map_err(|_| RejectReason::ServerError)?
+                    .join(
+                        sanitize_name(&login.name),
+                        peer_ip,
+                        login.previous_id,
+                        number,
+                    )?;
+                *assigned = Some(player_id);
+                Ok(player_id)
+            })
+            .map_err(|err| format!("handshake failed : {err}"))?;
+
+        let Some(session) = session else {
+            return Ok(false);
+        };
+
+        log::info!(
+            "{} completed handshake with {:?} as player {}",
+            self.number,
+            session.capabilities,
+            session.id
+        );
+
+        self.handshake = None;
+        self.datagrams = session.capabilities.contains(Capabilities::UDP_POSITIONS);
+        self.heartbeat.received();
+
+        Ok(true)
+    }
+
+    fn serve(&mut self, players: &Arc<RwLock<PlayerTable>>) -> Result<bool, String> {
+        let Some(player_id) = self.id else {
+            return Err("no player id after the handshake".to_string());
+        };
+
+        let mut busy = false;
+
+        let changes = {
+            let players = players
+                .read()
+                .map_err(|err| format!("couldn't get lock : {err}"))?;
+
+            if players.connection_of(player_id) != Some(self.number) {
+                return Err("the player reconnected on another connection".to_string());
+            }
+
+            // sent on their own since udp clients never send positions over this stream
+            players.roster_changes(player_id, &mut self.known_players)
+        };
+
+        for change in changes {
+            busy = true;
+            self.send(&change)?;
+        }
+
+        loop {
+            let message = match self.connection.recv_message() {
+                Ok(message) => {
+                    self.heartbeat.received();
+                    busy = true;
+                    match message {
+                        Some(message) => message,
+                        None => continue,
+                    }
+                }
+                Err(err) if is_timeout(&err) => break,
+                Err(err) => return Err(format!("couldn't receive message : {err}")),
+            };
+
+            let reply = match message {
+                Message::Position(info) => {
+                    players
+                        .write()
+                        .map_err(|err| format!("couldn't get lock : {err}"))?
+                        .update(player_id, info);
+                    continue;
+                }
+                Message::Ping(nonce) => Message::Pong(nonce),
+                Message::Chat { text, .. } => {
+                    log::info!("{player_id} says : {text}");
+                    continue;
+                }
+                Message::Disconnect(reason) => return Err(format!("disconnected : {reason}")),
+                Message::Pong(_) => continue,
+                Message::Ack(tick) => {
+                    if !matches!(self.acked, Some(last) if !is_newer(tick, last)) {
+                        self.acked = Some(tick);
+                    }
+                    continue;
+                }
+                Message::RequestBaseline => {
+                    log::warn!("{player_id} lost track of the snapshots, sending a full one");
+                    self.acked = None;
+                    continue;
+                }
+                Message::Snapshot(_) | Message::Join { .. } | Message::Leave(_) => {
+                    log::warn!("{player_id} sent a message only the server should send");
+                    continue;
                 }
             };
 
-            _ = stream.write_all(&sendpackets);
+            self.send(&reply)?;
+        }
+
+        match self.heartbeat.poll() {
+            Ok(Some(ping)) => self.send(&ping)?,
+            Ok(None) => {}
+            Err(err) => return Err(format!("timed out : {err}")),
+        }
 
-            player_positions.clear();
+        Ok(busy)
+    }
 
-            wait(100);
+    fn send(&mut self, message: &Message) -> Result<(), String> {
+        self.connection
+            .send(message)
+            .map_err(|err| format!("couldn't send message : {err}"))
+    }
+
+    /// closes the connection, players that finished the handshake are told why first
+    fn disconnect(&mut self, reason: &str) {
+        if self.handshake.is_none() {
+            _ = self.send(&Message::Disconnect(reason.to_string()));
+            _ = self.connection.flush();
         }
+
+        // unread data makes some platforms reset the connection, which could eat the reason, but a peer
+        // that keeps writing can't be allowed to hold up everyone else
+        for _ in 0..MAX_DRAINED_FRAMES {
+            if self.connection.recv_frame().is_err() {
+                break;
+            }
+        }
+
+        self.connection.close();
     }
 }
 
-impl Drop for ConnectionWorker {
-    fn drop(&mut self) {
-        log::warn!("Shutting down worker {}", self.id);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::{
+        client_handshake, client_handshake_as,
+        tests::{wait_for, wait_until},
+        Action, Closer, HandshakeError, Hello, Login, MemoryConnection, SnapshotDelta,
+        MIN_COMPATIBLE_VERSION, PROTOCOL_MAGIC, PROTOCOL_VERSION,
+    };
+    use rrplug::prelude::wait;
+    use std::{
+        io::{self, Read},
+        net::TcpStream,
+        time::{Duration, Instant},
+    };
+
+    /// a connection the server picked up that didn't do the handshake yet
+    fn open_connection(server: &PlayerMirrorServer) -> MemoryConnection {
+        let (mut connection, remote) = MemoryConnection::pair();
+        server.accept(Box::new(remote)).unwrap();
+        connection
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        connection
+    }
 
-        if let Some(thread) = self.thread.take() {
-            thread.join().unwrap();
+    /// a client that made it through the handshake with the default login
+    fn connect_client(server: &PlayerMirrorServer) -> MemoryConnection {
+        let mut connection = open_connection(server);
+        client_handshake(&mut connection, &Login::default()).unwrap();
+        connection
+    }
+
+    /// skips everything that came in before the next snapshot
+    fn next_snapshot(connection: &mut MemoryConnection) -> SnapshotDelta {
+        loop {
+            if let Some(Message::Snapshot(delta)) = connection.recv_message().unwrap() {
+                return delta;
+            }
         }
     }
+
+    #[test]
+    fn silent_client_times_out() {
+        let mut server = PlayerMirrorServer::with_timeouts(Timeouts {
+            read: Duration::from_millis(20),
+            write: Duration::from_secs(1),
+            ping_interval: Duration::from_millis(50),
+            peer: Duration::from_millis(200),
+            connect: Duration::from_secs(1),
+        });
+        server.bind("127.0.0.1:0".to_string()).unwrap();
+
+        let mut stream =
+            FramedStream::new(TcpStream::connect(server.local_addr().unwrap()).unwrap());
+        client_handshake(&mut stream, &Login::default()).unwrap();
+
+        assert_eq!(server.players.read().unwrap().roster().count(), 2);
+        assert_eq!(server.accept_status().accepted, 1);
+
+        // never answer anything and wait for the server to hang up
+        let start = Instant::now();
+        let mut buffer = [0; 64];
+        let mut raw = stream.get_ref().try_clone().unwrap();
+        raw.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        while let Ok(1..) = raw.read(&mut buffer) {}
+
+        assert!(start.elapsed() < Duration::from_secs(2));
+        wait_until(|| server.players.read().unwrap().roster().count() == 1);
+    }
+
+    #[test]
+    fn snapshots_go_out_every_tick() {
+        let mut server = PlayerMirrorServer::new();
+        server.set_name("hosty").unwrap();
+        server.set_tick_rate(TickRate::new(60).unwrap()).unwrap();
+
+        let mut connection = open_connection(&server);
+        let session = client_handshake(
+            &mut connection,
+            &Login {
+                name: "runner".to_string(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(session.id, HOST_ID + 1);
+
+        let info = PlayerInfo {
+            action: crate::shared::Action::Jump,
+            ..Default::default()
+        };
+        connection.send(&Message::Position(info.clone())).unwrap();
+
+        assert_eq!(
+            connection.recv_message().unwrap(),
+            Some(Message::Join {
+                id: HOST_ID,
+                name: "hosty".to_string()
+            })
+        );
+
+        // they keep coming without the client sending anything else
+        let host = PlayerInfo {
+            action: crate::shared::Action::Crouch,
+            ..Default::default()
+        };
+        server.push_position_to_streams(host.clone()).unwrap();
+        let mut history = SnapshotHistory::new();
+        let mut last_tick = None;
+        loop {
+            let Some(Message::Snapshot(delta)) = connection.recv_message().unwrap() else {
+                continue;
+            };
+            let snapshot = history.apply(delta).unwrap();
+
+            if let Some(last) = last_tick {
+                assert!(is_newer(snapshot.tick, last));
+            }
+            last_tick = Some(snapshot.tick);
+
+            // the host's position was stamped on the way in
+            if snapshot
+                .players
+                .iter()
+                .any(|(id, player)| *id == HOST_ID && player.action == host.action)
+            {
+                assert!(snapshot.players.contains(&(session.id, info.clone())));
+                break;
+            }
+        }
+
+        // ghosts trail the ticks a little so they have something to blend between
+        let ghosts = wait_for(|| {
+            let ghosts = server.get_positions_from_streams().unwrap();
+            ghosts
+                .first()
+                .is_some_and(|ghost| ghost.info == info)
+                .then_some(ghosts)
+        });
+        assert_eq!(ghosts.len(), 1);
+        assert_eq!(ghosts[0].id, session.id);
+        assert_eq!(ghosts[0].name, "runner");
+
+        connection
+            .send(&Message::Disconnect("bye".to_string()))
+            .unwrap();
+
+        wait_until(|| server.players.read().unwrap().roster().count() == 1);
+    }
+
+    #[test]
+    fn every_client_gets_the_same_snapshot() {
+        let server = PlayerMirrorServer::new();
+        server.set_tick_rate(TickRate::new(60).unwrap()).unwrap();
+
+        let mut connections = [connect_client(&server), connect_client(&server)];
+
+        // the second one joined later so the first one saw every tick it did
+        let second = next_snapshot(&mut connections[1]);
+        let first = loop {
+            let snapshot = next_snapshot(&mut connections[0]);
+            if snapshot.tick == second.tick {
+                break snapshot;
+            }
+        };
+
+        assert_eq!(first, second);
+        assert_eq!(first.changed.len(), 3);
+    }
+
+    #[test]
+    fn acked_snapshots_turn_into_deltas() {
+        let server = PlayerMirrorServer::new();
+        server.set_tick_rate(TickRate::new(60).unwrap()).unwrap();
+
+        let mut connection = connect_client(&server);
+
+        // nothing was acked yet so the first one is a full baseline
+        let full = next_snapshot(&mut connection);
+        assert_eq!(full.baseline, None);
+        assert_eq!(full.changed.len(), 2);
+
+        let mut history = SnapshotHistory::new();
+        history.apply(full.clone()).unwrap();
+        connection.send(&Message::Ack(full.tick)).unwrap();
+
+        let delta = loop {
+            let delta = next_snapshot(&mut connection);
+            if delta.baseline.is_some() {
+                break delta;
+            }
+        };
+        assert_eq!(delta.baseline, Some(full.tick));
+        assert!(delta.changed.is_empty() && delta.removed.is_empty()); // nobody moved
+
+        let host = PlayerInfo {
+            action: crate::shared::Action::Jump,
+            ..Default::default()
+        };
+        server.push_position_to_streams(host.clone()).unwrap();
+
+        let snapshot = loop {
+            let delta = next_snapshot(&mut connection);
+            if !delta.changed.is_empty() {
+                assert_eq!(delta.changed.len(), 1);
+                assert_eq!(delta.changed[0].0, HOST_ID);
+                assert!(delta.changed[0].1.same_pose(&host.clone().into()));
+                break history.apply(delta).unwrap();
+            }
+        };
+        assert!(snapshot
+            .players
+            .iter()
+            .any(|(id, player)| *id == HOST_ID && player.action == host.action));
+
+        connection.send(&Message::RequestBaseline).unwrap();
+        while next_snapshot(&mut connection).baseline.is_some() {}
+    }
+
+    #[test]
+    fn other_versions_are_told_why_they_are_rejected() {
+        let mut server = PlayerMirrorServer::new();
+        server.bind("127.0.0.1:0".to_string()).unwrap();
+
+        let handshake = |hello: Hello| {
+            let mut stream =
+                FramedStream::new(TcpStream::connect(server.local_addr().unwrap()).unwrap());
+            stream
+                .set_read_timeout(Some(Duration::from_secs(2)))
+                .unwrap();
+            client_handshake_as(&mut stream, &hello, &Login::default()).unwrap_err()
+        };
+
+        assert_eq!(
+            handshake(Hello {
+                version: MIN_COMPATIBLE_VERSION - 1,
+                ..Hello::new()
+            }),
+            HandshakeError::Rejected(RejectReason::VersionMismatch {
+                server: PROTOCOL_VERSION,
+                client: MIN_COMPATIBLE_VERSION - 1
+            })
+        );
+        assert_eq!(
+            handshake(Hello {
+                magic: !PROTOCOL_MAGIC,
+                ..Hello::new()
+            }),
+            HandshakeError::Rejected(RejectReason::BadMagic(!PROTOCOL_MAGIC))
+        );
+
+        assert_eq!(server.players.read().unwrap().roster().count(), 1);
+    }
+
+    #[test]
+    fn newer_versions_get_through_with_actions_we_dont_know() {
+        let mut server = PlayerMirrorServer::new();
+
+        let mut connection = open_connection(&server);
+        let hello = Hello {
+            version: PROTOCOL_VERSION + 1,
+            ..Hello::new()
+        };
+        let session = client_handshake_as(&mut connection, &hello, &Login::default()).unwrap();
+
+        let info = PlayerInfo {
+            action: Action::Unknown(1000),
+            sequence: 1,
+            ..Default::default()
+        };
+        connection.send(&Message::Position(info)).unwrap();
+
+        // the player shows up as soon as they join, the position only a bit later
+        wait_until(|| {
+            server
+                .get_positions_from_streams()
+                .unwrap()
+                .iter()
+                .any(|ghost| ghost.id == session.id && ghost.info.action == Action::Unknown(1000))
+        });
+    }
+
+    #[test]
+    fn full_servers_reject_new_players() {
+        let server = PlayerMirrorServer::new();
+        server.set_max_players(2).unwrap();
+
+        let mut connections = [open_connection(&server), open_connection(&server)];
+
+        assert!(client_handshake(&mut connections[0], &Login::default()).is_ok());
+        assert_eq!(
+            client_handshake(&mut connections[1], &Login::default()).unwrap_err(),
+            HandshakeError::Rejected(RejectReason::ServerFull { max_players: 2 })
+        );
+        assert_eq!(server.players.read().unwrap().roster().count(), 2);
+    }
+
+    #[test]
+    fn reconnecting_players_keep_their_id() {
+        let server = PlayerMirrorServer::new();
+
+        let mut connections = [(); 3].map(|_| open_connection(&server));
+
+        let id = client_handshake(&mut connections[0], &Login::default())
+            .unwrap()
+            .id;
+
+        // the first connection is still open, like one the server didn't notice breaking yet
+        let rejoin = Login {
+            previous_id: Some(id),
+            ..Default::default()
+        };
+        assert_eq!(
+            client_handshake(&mut connections[1], &rejoin).unwrap().id,
+            id
+        );
+
+        // the old connection is dropped without taking the player along
+        while connections[0].recv_frame().is_ok() {}
+        wait(20);
+        assert_eq!(server.players.read().unwrap().roster().count(), 2);
+
+        // ids the server doesn't have aren't handed out
+        let unknown = Login {
+            previous_id: Some(id + 5),
+            ..Default::default()
+        };
+        assert_eq!(
+            client_handshake(&mut connections[2], &unknown).unwrap().id,
+            id + 1
+        );
+    }
+
+    #[test]
+    fn stale_positions_are_dropped() {
+        let mut players = PlayerTable::new();
+        let ip = "127.0.0.1".parse().unwrap();
+        let id = players.join(None, Some(ip), None, 0).unwrap();
+        let info = |sequence| PlayerInfo {
+            sequence,
+            ..Default::default()
+        };
+
+        assert!(players.update(id, info(5)));
+        assert!(!players.update(id, info(5)));
+        assert!(!players.update(id, info(4)));
+
+        // the same goes for datagrams, which also have to come from the player's address
+        let from = SocketAddr::new(ip, 1234);
+        assert!(!players.update_from_datagram(id, from, info(3)));
+        assert!(!players.update_from_datagram(id, "10.0.0.1:1234".parse().unwrap(), info(6)));
+        assert!(players.update_from_datagram(id, from, info(6)));
+        assert_eq!(players.datagram_addr(id), Some(from));
+
+        // coming back starts over, the client may have restarted
+        players.join(None, Some(ip), Some(id), 1).unwrap();
+        assert!(players.update(id, info(1)));
+    }
+
+    #[test]
+    fn shutdown_stops_accepting() {
+        let mut server = PlayerMirrorServer::new();
+        server.bind("127.0.0.1:0".to_string()).unwrap();
+        let address = server.local_addr().unwrap();
+
+        assert_eq!(server.state(), ServerState::Listening);
+
+        server.shutdown();
+        assert!(!server.is_listening());
+        assert_eq!(server.state(), ServerState::Idle);
+        assert!(TcpStream::connect(address).is_err());
+
+        // the port is free again once the acceptor is gone
+        server.bind(address.to_string()).unwrap();
+        assert!(TcpStream::connect(address).is_ok());
+        assert_eq!(server.accept_status().failures, 0);
+
+        let mut other = PlayerMirrorServer::new();
+        assert!(other.bind(address.to_string()).is_err());
+        assert!(matches!(other.state(), ServerState::Failed(_)));
+    }
+
+    /// a peer that never stops writing once it's told to go away
+    #[derive(Debug)]
+    struct Stubborn {
+        connection: MemoryConnection,
+        disconnected: bool,
+    }
+
+    impl Connection for Stubborn {
+        fn send_frame(&mut self, payload: &[u8]) -> io::Result<()> {
+            if let Ok(Message::Disconnect(_)) = bincode::deserialize(payload) {
+                self.disconnected = true;
+            }
+            self.connection.send_frame(payload)
+        }
+
+        fn recv_frame(&mut self) -> io::Result<Vec<u8>> {
+            match self.disconnected {
+                true => Ok(bincode::serialize(&Message::Ping(0)).unwrap()),
+                false => self.connection.recv_frame(),
+            }
+        }
+
+        fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+            self.connection.set_read_timeout(timeout)
+        }
+
+        fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+            self.connection.set_write_timeout(timeout)
+        }
+
+        fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+            self.connection.set_nonblocking(nonblocking)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.connection.flush()
+        }
+
+        fn peer_addr(&self) -> Option<SocketAddr> {
+            self.connection.peer_addr()
+        }
+
+        fn closer(&self) -> Option<Closer> {
+            self.connection.closer()
+        }
+
+        fn close(&mut self) {
+            self.connection.close()
+        }
+    }
+
+    #[test]
+    fn flooding_peers_dont_hold_up_disconnects() {
+        let mut server = PlayerMirrorServer::new();
+
+        let (mut stubborn, remote) = MemoryConnection::pair();
+        server
+            .accept(Box::new(Stubborn {
+                connection: remote,
+                disconnected: false,
+            }))
+            .unwrap();
+        stubborn
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        client_handshake(&mut stubborn, &Login::default()).unwrap();
+        let mut connection = connect_client(&server);
+
+        server.shutdown();
+
+        let reason = loop {
+            if let Some(Message::Disconnect(reason)) = connection.recv_message().unwrap() {
+                break reason;
+            }
+        };
+        assert_eq!(reason, SERVER_CLOSING);
+    }
+
+    #[test]
+    fn shutdown_disconnects_players() {
+        let mut server = PlayerMirrorServer::new();
+
+        let mut connection = connect_client(&server);
+
+        server
+            .push_position_to_streams(PlayerInfo {
+                action: crate::shared::Action::Jump,
+                ..Default::default()
+            })
+            .unwrap();
+        server.shutdown();
+
+        let reason = loop {
+            if let Some(Message::Disconnect(reason)) = connection.recv_message().unwrap() {
+                break reason;
+            }
+        };
+        assert_eq!(reason, SERVER_CLOSING);
+        assert_eq!(
+            connection.recv_frame().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        let players = server.players.read().unwrap();
+        assert_eq!(players.roster().count(), 1);
+        assert_eq!(
+            players.snapshot(Some(HOST_ID + 1)),
+            vec![(HOST_ID, PlayerInfo::default())]
+        );
+    }
 }
@@ -1,24 +1,76 @@
 use crate::{client::PlayerMirrorClient, server::PlayerMirrorServer};
 use rrplug::wrappers::vector::Vector3;
-use serde::{Deserialize, Serialize};
-use std::mem::transmute;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::{self, Display},
+    io::{self, Read, Write},
+    mem::transmute,
+    net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+    ops::{Add, Mul, Sub},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
 
-pub const VEC_PACKET_SIZE: usize = 512;
-pub const SINGLE_PACKET_SIZE: usize = 32;
+/// first thing sent on every connection, spells out "PMIR"
+pub const PROTOCOL_MAGIC: u32 = u32::from_le_bytes(*b"PMIR");
+/// has to be bumped whenever the wire format changes
+pub const PROTOCOL_VERSION: u16 = 7;
+/// the oldest version we still talk to, only raised when a change can't be read by older peers
+///
+/// additions like new `Message` or `Action` variants don't count, peers skip or pass on what they don't know
+pub const MIN_COMPATIBLE_VERSION: u16 = 7;
+/// how long the server waits for a hello before giving up on a connection
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// longer names get cut off so they still fit above a ghost
+pub const MAX_NAME_LEN: usize = 32;
+/// how many players a server takes by default, the host included
+pub const DEFAULT_MAX_PLAYERS: usize = 16;
+/// how many times a second positions and snapshots go out by default
+pub const DEFAULT_TICK_RATE: u32 = 30;
+/// how long a worker gets to stop before it's left behind
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+/// how fast pilots fall in units per second squared, what `sv_gravity` defaults to
+pub const GRAVITY: f32 = 750.;
+/// how many snapshots are kept around to make deltas against, two seconds at the default tick rate
+pub const SNAPSHOT_HISTORY: usize = 64;
+/// how far behind the server ghosts are drawn, so there's usually a snapshot on either side to blend between
+pub const INTERPOLATION_DELAY: Duration = Duration::from_millis(100);
+
+/// size of the little endian length prefix in front of every frame
+pub const FRAME_HEADER_SIZE: usize = 4;
+/// anything bigger than this is treated as a corrupted stream
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+/// how much a connection queues up for a peer that stopped reading before giving up on it
+pub const MAX_WRITE_BACKLOG: usize = 4 * MAX_FRAME_SIZE;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct PlayerInfo {
     pub position: SerializableVector3,
     pub viewangle: SerializableVector3,
+    /// in units per second, ghosts keep going this way while updates are late
+    pub velocity: SerializableVector3,
     pub action: Action,
+    /// counts up with every update the sender takes, anything not newer than what a receiver has is stale
+    pub sequence: u32,
+    /// how long the sender had been running when this was taken
+    pub sent_at: Duration,
 }
 
 impl PlayerInfo {
-    pub fn new(position: Vector3, viewangle: Vector3, action: Action) -> Self {
+    pub fn new(position: Vector3, viewangle: Vector3, velocity: Vector3, action: Action) -> Self {
         Self {
             position: position.into(),
             viewangle: viewangle.into(),
+            velocity: velocity.into(),
             action,
+            sequence: 0,
+            sent_at: Duration::ZERO,
         }
     }
 
@@ -29,11 +81,49 @@ impl PlayerInfo {
     pub fn get_viewangle(&self) -> Vector3 {
         self.viewangle.clone().into()
     }
+
+    /// `t` of the way from `self` to `other`, the action and stamps can't be blended so they switch halfway
+    pub fn lerp(&self, other: &Self, t: f32) -> Self {
+        let nearest = if t < 0.5 { self } else { other };
+
+        Self {
+            position: self.position.lerp(&other.position, t),
+            viewangle: self.viewangle.lerp_angles(&other.viewangle, t),
+            velocity: self.velocity.lerp(&other.velocity, t),
+            action: nearest.action.clone(),
+            sequence: nearest.sequence,
+            sent_at: nearest.sent_at,
+        }
+    }
+
+    /// where the player would be after `seconds` if they kept going, standing and dead players stay put
+    /// and airborne ones fall
+    pub fn extrapolate(&self, seconds: f32) -> Self {
+        let mut info = self.clone();
+
+        match self.action {
+            Action::Stand | Action::Death => {}
+            Action::Jump | Action::DoubleJump => {
+                let fall = SerializableVector3 {
+                    x: 0.,
+                    y: 0.,
+                    z: -GRAVITY * seconds,
+                };
+                info.position =
+                    info.position + self.velocity.clone() * seconds + fall.clone() * (seconds / 2.);
+                info.velocity = info.velocity + fall;
+            }
+            _ => info.position = info.position + self.velocity.clone() * seconds,
+        }
+
+        info
+    }
 }
 
 impl Default for PlayerInfo {
     fn default() -> Self {
         Self::new(
+            Vector3::from([0., 0., 0.]),
             Vector3::from([0., 0., 0.]),
             Vector3::from([0., 0., 0.]),
             Action::Stand,
@@ -48,6 +138,65 @@ pub struct SerializableVector3 {
     pub z: f32,
 }
 
+impl SerializableVector3 {
+    pub fn lerp(&self, other: &Self, t: f32) -> Self {
+        let lerp = |from: f32, to: f32| from + (to - from) * t;
+
+        Self {
+            x: lerp(self.x, other.x),
+            y: lerp(self.y, other.y),
+            z: lerp(self.z, other.z),
+        }
+    }
+
+    /// like `lerp` but for angles in degrees, these take the short way around
+    pub fn lerp_angles(&self, other: &Self, t: f32) -> Self {
+        let lerp = |from: f32, to: f32| from + ((to - from + 180.).rem_euclid(360.) - 180.) * t;
+
+        Self {
+            x: lerp(self.x, other.x),
+            y: lerp(self.y, other.y),
+            z: lerp(self.z, other.z),
+        }
+    }
+}
+
+impl Add for SerializableVector3 {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z,
+        }
+    }
+}
+
+impl Sub for SerializableVector3 {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self {
+            x: self.x - other.x,
+            y: self.y - other.y,
+            z: self.z - other.z,
+        }
+    }
+}
+
+impl Mul<f32> for SerializableVector3 {
+    type Output = Self;
+
+    fn mul(self, factor: f32) -> Self {
+        Self {
+            x: self.x * factor,
+            y: self.y * factor,
+            z: self.z * factor,
+        }
+    }
+}
+
 impl From<Vector3> for SerializableVector3 {
     fn from(value: Vector3) -> Self {
         unsafe { transmute(value) }
@@ -61,26 +210,169 @@ impl Into<Vector3> for SerializableVector3 {
     }
 }
 
+/// spells out `Action` along with its numbers so the conversions both ways come from the same list
+macro_rules! actions {
+    ($($name:ident = $value:literal,)*) => {
+        /// what a player is doing, squirrel passes these around as numbers
+        ///
+        /// they go over the wire as those same numbers so ones from newer versions still make it through
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum Action {
+            $($name,)*
+            /// something only a newer version knows about, passed on as it came in
+            Unknown(i32),
+        }
+
+        impl TryFrom<i32> for Action {
+            type Error = UnknownAction;
+
+            fn try_from(value: i32) -> Result<Self, Self::Error> {
+                match value {
+                    $($value => Ok(Self::$name),)*
+                    _ => Err(UnknownAction(value)),
+                }
+            }
+        }
+
+        impl From<Action> for i32 {
+            fn from(action: Action) -> Self {
+                match action {
+                    $(Action::$name => $value,)*
+                    Action::Unknown(value) => value,
+                }
+            }
+        }
+    };
+}
+
+actions! {
+    Crouch = 0,
+    Run = 1,
+    Stand = 2,
+    Jump = 3,
+    WallrunRight = 4,
+    WallrunLeft = 5,
+    WallrunFront = 6,
+    WallrunBack = 7,
+    Slide = 8,
+    Walk = 9,
+    Sprint = 10,
+    DoubleJump = 11,
+    Mantle = 12,
+    Grapple = 13,
+    Zipline = 14,
+    Melee = 15,
+    Death = 16,
+    PhaseShift = 17,
+}
+
+impl Action {
+    /// the action `value` stands for, `Unknown` if it isn't one we know
+    pub fn or_unknown(value: i32) -> Self {
+        Self::try_from(value).unwrap_or(Self::Unknown(value))
+    }
+}
+
+impl Serialize for Action {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        i32::from(self.clone()).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Action {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        i32::deserialize(deserializer).map(Self::or_unknown)
+    }
+}
+
+/// a number that isn't any `Action`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownAction(pub i32);
+
+impl Display for UnknownAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} isn't an action", self.0)
+    }
+}
+
+/// positions go out in sixteenths of a unit
+const POSITION_STEPS: f32 = 16.;
+/// angles go out as an `i16` covering -180 to 180 degrees
+const ANGLE_STEPS: f32 = 32768. / 180.;
+
+/// a `PlayerInfo` rounded to what goes over the wire
+///
+/// changes smaller than the rounding don't make a player show up in a delta
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
-#[repr(i32)]
-pub enum Action {
-    Crouch,
-    Run,
-    Stand,
-    Jump,
-    WallrunRight,
-    WallrunLeft,
-    WallrunFront,
-    WallrunBack,
-}
-
-impl From<i32> for Action {
-    fn from(value: i32) -> Self {
-        if value <= 7 {
-            // this the max Action can be with WallrunBack == 7
-            unsafe { std::mem::transmute(value) }
-        } else {
-            Self::Stand
+pub struct QuantizedInfo {
+    pub position: [i32; 3],
+    pub viewangle: [i16; 3],
+    /// in whole units per second
+    pub velocity: [i16; 3],
+    pub action: Action,
+    pub sequence: u32,
+    /// in milliseconds
+    pub sent_at: u32,
+}
+
+impl QuantizedInfo {
+    /// whether both put the player in the same spot doing the same thing, the stamps don't count
+    ///
+    /// otherwise everyone would be in every delta since their sequence goes up every tick
+    pub fn same_pose(&self, other: &Self) -> bool {
+        self.position == other.position
+            && self.viewangle == other.viewangle
+            && self.velocity == other.velocity
+            && self.action == other.action
+    }
+}
+
+impl From<PlayerInfo> for QuantizedInfo {
+    fn from(info: PlayerInfo) -> Self {
+        let position = |value: f32| (value * POSITION_STEPS).round() as i32;
+        // wrapped into -180..180 first, 540 and -180 are the same angle
+        let angle =
+            |value: f32| (((value + 180.).rem_euclid(360.) - 180.) * ANGLE_STEPS).round() as i16;
+
+        Self {
+            position: [
+                position(info.position.x),
+                position(info.position.y),
+                position(info.position.z),
+            ],
+            viewangle: [
+                angle(info.viewangle.x),
+                angle(info.viewangle.y),
+                angle(info.viewangle.z),
+            ],
+            velocity: [
+                info.velocity.x.round() as i16,
+                info.velocity.y.round() as i16,
+                info.velocity.z.round() as i16,
+            ],
+            action: info.action,
+            sequence: info.sequence,
+            sent_at: info.sent_at.as_millis().try_into().unwrap_or(u32::MAX),
+        }
+    }
+}
+
+impl From<QuantizedInfo> for PlayerInfo {
+    fn from(info: QuantizedInfo) -> Self {
+        let [x, y, z] = info.position.map(|value| value as f32 / POSITION_STEPS);
+        let position = SerializableVector3 { x, y, z };
+        let [x, y, z] = info.viewangle.map(|value| value as f32 / ANGLE_STEPS);
+        let viewangle = SerializableVector3 { x, y, z };
+        let [x, y, z] = info.velocity.map(f32::from);
+        let velocity = SerializableVector3 { x, y, z };
+
+        Self {
+            position,
+            viewangle,
+            velocity,
+            action: info.action,
+            sequence: info.sequence,
+            sent_at: Duration::from_millis(info.sent_at.into()),
         }
     }
 }
@@ -91,10 +383,2038 @@ pub enum MirroringType {
     Client(PlayerMirrorClient),
 }
 
-pub type PlayerInfoArray = [PlayerInfo; 16];
+impl MirroringType {
+    /// the client, a running server is shut down and replaced by a new one first
+    ///
+    /// the server's dummies are handed over so the client hides them
+    pub fn client(&mut self) -> &mut PlayerMirrorClient {
+        if let Self::Server(server) = self {
+            server.shutdown();
+            let mut client = PlayerMirrorClient::new();
+            client.hide_ghosts(server.release_ghosts());
+            *self = Self::Client(client);
+        }
+
+        match self {
+            Self::Client(client) => client,
+            Self::Server(_) => unreachable!(),
+        }
+    }
+
+    /// what the current mode is up to, shown to squirrel through `MirrorGetStatus`
+    pub fn status(&self) -> String {
+        match self {
+            Self::Server(server) => {
+                let accept_status = server.accept_status();
+                match accept_status.last_error {
+                    Some(err) => format!(
+                        "server {} ({} accepts failed, last : {err})",
+                        server.state(),
+                        accept_status.failures
+                    ),
+                    None => format!("server {}", server.state()),
+                }
+            }
+            Self::Client(client) => format!("client {}", client.state()),
+        }
+    }
+
+    /// the server, a client is disconnected and replaced by a new one first
+    ///
+    /// the client's dummies are handed over so the server hides them
+    pub fn server(&mut self) -> &mut PlayerMirrorServer {
+        if let Self::Client(client) = self {
+            client.shutdown();
+            let mut server = PlayerMirrorServer::new();
+            server.hide_ghosts(client.release_ghosts());
+            *self = Self::Server(server);
+        }
+
+        match self {
+            Self::Server(server) => server,
+            Self::Client(_) => unreachable!(),
+        }
+    }
+}
+
+pub type PlayerId = u32;
+
+/// the player hosting the server, connections get ids counting up from here
+pub const HOST_ID: PlayerId = 0;
+
+/// a remote player bound to one of the dummies on the squirrel side
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ghost {
+    pub index: i32,
+    pub id: PlayerId,
+    pub name: String,
+    pub info: PlayerInfo,
+}
+
+/// keeps every player on the same dummy for as long as they stay around
+#[derive(Debug, Default)]
+pub struct GhostSlots {
+    slots: Vec<Option<PlayerId>>,
+    released: Vec<i32>,
+}
+
+impl GhostSlots {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// frees the dummies of players that are gone and hands the lowest free ones to new players
+    pub fn ghosts(
+        &mut self,
+        players: Vec<(PlayerId, PlayerInfo)>,
+        name_of: impl Fn(PlayerId) -> String,
+    ) -> Vec<Ghost> {
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if matches!(slot, Some(id) if !players.iter().any(|(other, _)| other == id)) {
+                *slot = None;
+                self.released.push(index as i32);
+            }
+        }
+
+        players
+            .into_iter()
+            .map(|(id, info)| Ghost {
+                index: self.index_of(id) as i32,
+                id,
+                name: name_of(id),
+                info,
+            })
+            .collect()
+    }
+
+    /// dummies that lost their player since the last call
+    pub fn take_released(&mut self) -> Vec<i32> {
+        std::mem::take(&mut self.released)
+    }
+
+    /// frees every dummy and returns them along with the ones that weren't taken yet
+    pub fn release_all(&mut self) -> Vec<i32> {
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if slot.take().is_some() {
+                self.released.push(index as i32);
+            }
+        }
+
+        self.take_released()
+    }
+
+    /// queues dummies to be returned by the next `take_released`
+    pub fn hide(&mut self, indexes: Vec<i32>) {
+        for index in indexes {
+            if !self.released.contains(&index) {
+                self.released.push(index);
+            }
+        }
+    }
+
+    fn index_of(&mut self, id: PlayerId) -> usize {
+        if let Some(index) = self.slots.iter().position(|slot| *slot == Some(id)) {
+            return index;
+        }
+
+        match self.slots.iter().position(Option::is_none) {
+            Some(index) => {
+                self.slots[index] = Some(id);
+                index
+            }
+            None => {
+                self.slots.push(Some(id));
+                self.slots.len() - 1
+            }
+        }
+    }
+}
+
+/// everything that can be sent after the handshake
+///
+/// new variants must only be appended, peers skip frames holding variants they don't know
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Message {
+    /// the sender's own player
+    Position(PlayerInfo),
+    Snapshot(SnapshotDelta),
+    Join {
+        id: PlayerId,
+        name: String,
+    },
+    Leave(PlayerId),
+    Chat {
+        id: PlayerId,
+        text: String,
+    },
+    Ping(u64),
+    Pong(u64),
+    Disconnect(String),
+    /// the last snapshot the client could rebuild, the next ones are deltas against it
+    Ack(u32),
+    /// a delta didn't fit any snapshot the client has, the next one has to be a full one
+    RequestBaseline,
+}
+
+/// every player on the server as of one server tick, everyone gets the same one
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Snapshot {
+    /// counts up once per tick, see `is_newer`
+    pub tick: u32,
+    /// how long the server had been running when this was taken
+    pub time: Duration,
+    pub players: Vec<(PlayerId, PlayerInfo)>,
+}
+
+/// a snapshot as it goes over the wire, only holding what changed since `baseline`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct SnapshotDelta {
+    pub tick: u32,
+    pub time: Duration,
+    /// the tick this was made against, `None` for a full snapshot
+    pub baseline: Option<u32>,
+    /// players that joined or changed since the baseline
+    pub changed: Vec<(PlayerId, QuantizedInfo)>,
+    /// players from the baseline that are gone
+    pub removed: Vec<PlayerId>,
+}
+
+#[derive(Debug, Clone)]
+struct QuantizedSnapshot {
+    tick: u32,
+    time: Duration,
+    players: BTreeMap<PlayerId, QuantizedInfo>,
+}
+
+/// the last few snapshots one side sent or got, deltas are made against these
+#[derive(Debug, Default)]
+pub struct SnapshotHistory {
+    snapshots: VecDeque<QuantizedSnapshot>,
+}
+
+impl SnapshotHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the oldest snapshot is forgotten once there are more than `SNAPSHOT_HISTORY`
+    pub fn push(&mut self, snapshot: Snapshot) {
+        self.remember(QuantizedSnapshot {
+            tick: snapshot.tick,
+            time: snapshot.time,
+            players: snapshot
+                .players
+                .into_iter()
+                .map(|(id, info)| (id, info.into()))
+                .collect(),
+        })
+    }
+
+    /// `acked` if it's still around to make a delta against
+    pub fn baseline(&self, acked: Option<u32>) -> Option<u32> {
+        acked.filter(|tick| self.get(*tick).is_some())
+    }
+
+    /// the newest snapshot as a delta against `baseline`, a full one if that isn't around anymore
+    pub fn delta(&self, baseline: Option<u32>) -> Option<SnapshotDelta> {
+        let newest = self.snapshots.back()?;
+        let Some(base) = baseline.and_then(|tick| self.get(tick)) else {
+            return Some(SnapshotDelta {
+                tick: newest.tick,
+                time: newest.time,
+                baseline: None,
+                changed: newest.players.clone().into_iter().collect(),
+                removed: Vec::new(),
+            });
+        };
+
+        Some(SnapshotDelta {
+            tick: newest.tick,
+            time: newest.time,
+            baseline: Some(base.tick),
+            changed: newest
+                .players
+                .iter()
+                .filter(|(id, info)| {
+                    !base
+                        .players
+                        .get(id)
+                        .is_some_and(|base| base.same_pose(info))
+                })
+                .map(|(id, info)| (*id, info.clone()))
+                .collect(),
+            removed: base
+                .players
+                .keys()
+                .filter(|id| !newest.players.contains_key(id))
+                .copied()
+                .collect(),
+        })
+    }
+
+    /// rebuilds the snapshot a delta was made from and remembers it, fails if its baseline is gone
+    pub fn apply(&mut self, delta: SnapshotDelta) -> Result<Snapshot, String> {
+        let mut players = match delta.baseline {
+            Some(tick) => match self.get(tick) {
+                Some(base) => base.players.clone(),
+                None => {
+                    return Err(format!(
+                        "snapshot {} was made against {tick} which is gone",
+                        delta.tick
+                    ))
+                }
+            },
+            None => BTreeMap::new(),
+        };
+
+        for id in delta.removed {
+            players.remove(&id);
+        }
+        players.extend(delta.changed);
+
+        let snapshot = Snapshot {
+            tick: delta.tick,
+            time: delta.time,
+            players: players
+                .iter()
+                .map(|(id, info)| (*id, info.clone().into()))
+                .collect(),
+        };
+
+        self.remember(QuantizedSnapshot {
+            tick: delta.tick,
+            time: delta.time,
+            players,
+        });
+
+        Ok(snapshot)
+    }
+
+    fn remember(&mut self, snapshot: QuantizedSnapshot) {
+        if self.snapshots.len() >= SNAPSHOT_HISTORY {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(snapshot);
+    }
+
+    fn get(&self, tick: u32) -> Option<&QuantizedSnapshot> {
+        self.snapshots.iter().find(|snapshot| snapshot.tick == tick)
+    }
+}
+
+/// how long positions are kept around to blend between, in seconds
+const INTERPOLATION_HISTORY: f64 = 1.;
+/// clock estimates that are further ahead than this are taken as they are instead of being eased into, in seconds
+const MAX_CLOCK_DRIFT: f64 = 0.25;
+/// how much of the way to a new clock estimate is taken with every snapshot
+const CLOCK_SMOOTHING: f64 = 0.1;
+/// the same for snapshots that came in later than expected, those usually just got stuck somewhere
+const LATE_CLOCK_SMOOTHING: f64 = 0.002;
+
+/// how far ghosts keep going on their own when updates are late
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extrapolation {
+    /// how long a ghost moves on past the newest snapshot before it stops and waits
+    pub max: Duration,
+    /// how long a ghost takes to get back on track once the real position shows up, zero snaps it back
+    pub blend: Duration,
+}
+
+impl Default for Extrapolation {
+    fn default() -> Self {
+        Self {
+            max: Duration::from_millis(250),
+            blend: Duration::from_millis(100),
+        }
+    }
+}
+
+/// the samples of one player and what was made of them last frame
+#[derive(Debug, Default)]
+struct Track {
+    /// server time and where the player was, oldest first
+    samples: VecDeque<(f64, PlayerInfo)>,
+    /// the sample the last frame was extrapolated from
+    extrapolated_from: Option<f64>,
+    /// how far the ghost was off when real data showed up and when that was, fades out over `Extrapolation::blend`
+    correction: Option<(f64, SerializableVector3)>,
+}
+
+impl Track {
+    /// blends the two samples around `time`, past the newest one the player is moved on for up to `max`
+    fn target(&self, time: f64, max: f64) -> Option<PlayerInfo> {
+        match self.samples.iter().position(|(at, _)| *at > time) {
+            Some(0) => self.samples.front().map(|(_, info)| info.clone()),
+            Some(next) => {
+                let (from_time, from) = &self.samples[next - 1];
+                let (to_time, to) = &self.samples[next];
+                Some(from.lerp(to, ((time - from_time) / (to_time - from_time)) as f32))
+            }
+            None => {
+                let (newest_time, newest) = self.samples.back()?;
+                Some(newest.extrapolate((time - newest_time).min(max) as f32))
+            }
+        }
+    }
+
+    fn sample(&mut self, time: f64, extrapolation: Extrapolation) -> Option<PlayerInfo> {
+        let max = extrapolation.max.as_secs_f64();
+        let mut info = self.target(time, max)?;
+
+        let newest = self.samples.back().map(|(at, _)| *at);
+        let extrapolating = newest.filter(|newest| *newest < time);
+
+        // new data showed up, wherever the old guess would have gone is where the ghost blends back from
+        if let Some(from) = self
+            .extrapolated_from
+            .filter(|from| extrapolating != Some(*from))
+        {
+            if let Some((_, guessed_from)) = self.samples.iter().find(|(at, _)| *at == from) {
+                let mut guess = guessed_from
+                    .extrapolate((time - from).min(max) as f32)
+                    .position;
+                if let Some(correction) = self.correction_at(time, extrapolation.blend) {
+                    guess = guess + correction;
+                }
+
+                self.correction = Some((time, guess - info.position.clone()));
+            }
+        }
+
+        match self.correction_at(time, extrapolation.blend) {
+            Some(correction) => info.position = info.position + correction,
+            None => self.correction = None,
+        }
+
+        self.extrapolated_from = extrapolating;
+        Some(info)
+    }
+
+    /// what's left of the last correction at `time`
+    fn correction_at(&self, time: f64, blend: Duration) -> Option<SerializableVector3> {
+        if blend.is_zero() {
+            return None;
+        }
+
+        let (since, offset) = self.correction.as_ref()?;
+        let left = 1. - (time - since) / blend.as_secs_f64();
+
+        (left > 0.).then(|| offset.clone() * left as f32)
+    }
+}
+
+/// the last few positions of every player along with the server time they were at
+///
+/// ghosts are drawn `INTERPOLATION_DELAY` behind the server so they move smoothly even when snapshots
+/// show up unevenly, when one is late they keep going for a bit based on how they were moving
+#[derive(Debug, Default)]
+pub struct Interpolation {
+    /// what local times are counted from, the first snapshot's arrival
+    started: Option<Instant>,
+    /// the server's clock minus ours in seconds, eased so jitter doesn't make ghosts jump around
+    clock_offset: Option<f64>,
+    /// the last frame's render time, ghosts never go back from it
+    render_time: f64,
+    players: BTreeMap<PlayerId, Track>,
+    extrapolation: Extrapolation,
+}
+
+impl Interpolation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_extrapolation(&mut self, extrapolation: Extrapolation) {
+        self.extrapolation = extrapolation
+    }
+
+    /// remembers where everyone was at `server_time`, players missing from `players` are forgotten
+    ///
+    /// `now` is when the snapshot came in
+    pub fn push(
+        &mut self,
+        server_time: Duration,
+        players: &[(PlayerId, PlayerInfo)],
+        now: Instant,
+    ) {
+        self.started.get_or_insert(now);
+
+        let time = server_time.as_secs_f64();
+        let offset = time - self.local_time(now);
+
+        self.clock_offset = Some(match self.clock_offset {
+            Some(last) if offset < last => last + (offset - last) * LATE_CLOCK_SMOOTHING,
+            Some(last) if offset - last <= MAX_CLOCK_DRIFT => {
+                last + (offset - last) * CLOCK_SMOOTHING
+            }
+            _ => offset,
+        });
+
+        self.players
+            .retain(|id, _| players.iter().any(|(other, _)| other == id));
 
+        for (id, info) in players {
+            let samples = &mut self.players.entry(*id).or_default().samples;
+
+            // a late one would only send the ghost backwards
+            if matches!(samples.back(), Some((last, newest)) if *last >= time || is_newer(newest.sequence, info.sequence))
+            {
+                continue;
+            }
+
+            samples.push_back((time, info.clone()));
+
+            while samples
+                .front()
+                .is_some_and(|(oldest, _)| time - oldest > INTERPOLATION_HISTORY)
+            {
+                samples.pop_front();
+            }
+        }
+    }
+
+    /// where everyone was `INTERPOLATION_DELAY` ago on the server's clock
+    pub fn sample(&mut self, now: Instant) -> Vec<(PlayerId, PlayerInfo)> {
+        let Some(offset) = self.clock_offset else {
+            return Vec::new();
+        };
+        let render_time = (self.local_time(now) + offset - INTERPOLATION_DELAY.as_secs_f64())
+            .max(self.render_time);
+        self.render_time = render_time;
+        let extrapolation = self.extrapolation;
+
+        self.players
+            .iter_mut()
+            .filter_map(|(id, track)| Some((*id, track.sample(render_time, extrapolation)?)))
+            .collect()
+    }
+
+    pub fn remove(&mut self, id: PlayerId) {
+        self.players.remove(&id);
+    }
+
+    /// forgets every player and the server's clock but keeps the limits
+    pub fn clear(&mut self) {
+        *self = Self {
+            extrapolation: self.extrapolation,
+            ..Self::default()
+        }
+    }
+
+    fn local_time(&self, now: Instant) -> f64 {
+        self.started.map_or(0., |started| {
+            now.saturating_duration_since(started).as_secs_f64()
+        })
+    }
+}
+
+#[derive(Debug)]
 pub enum WorkerMessage {
-    Work(std::net::TcpStream),
+    /// a connection to serve and, if it can be redone, how to get another one once it's lost
+    Work(Box<dyn Connection>, Option<Dialer>),
+    /// opens a connection first, failing to do so only shows up in the client's state
+    Connect(Dialer),
     Death,
     EndJob,
 }
+
+/// optional protocol features, both sides end up using what they have in common
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    /// positions go over udp while everything else stays on the tcp stream
+    pub const UDP_POSITIONS: Self = Self(1);
+
+    /// what this build supports
+    pub const fn local() -> Self {
+        Self::UDP_POSITIONS
+    }
+
+    pub const fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// the layout of this can never change or older peers won't be able to read the rejection
+///
+/// the same goes for the order of the `HandshakeReply` variants and `RejectReason`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Hello {
+    pub magic: u32,
+    pub version: u16,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    pub fn new() -> Self {
+        Self {
+            magic: PROTOCOL_MAGIC,
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::local(),
+        }
+    }
+
+    pub fn check(&self) -> Result<(), RejectReason> {
+        if self.magic != PROTOCOL_MAGIC {
+            return Err(RejectReason::BadMagic(self.magic));
+        }
+
+        if self.version < MIN_COMPATIBLE_VERSION {
+            return Err(RejectReason::VersionMismatch {
+                server: PROTOCOL_VERSION,
+                client: self.version,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum HandshakeReply {
+    Welcome {
+        version: u16,
+        capabilities: Capabilities,
+        id: PlayerId,
+    },
+    Rejected(RejectReason),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RejectReason {
+    BadMagic(u32),
+    VersionMismatch { server: u16, client: u16 },
+    ServerError,
+    ServerFull { max_players: u32 },
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic(magic) => write!(f, "not a player mirror peer (magic {magic:#x})"),
+            Self::VersionMismatch { server, client } => write!(
+                f,
+                "protocol version mismatch : server runs v{server}, client runs v{client}"
+            ),
+            Self::ServerError => write!(f, "the server couldn't register the player"),
+            Self::ServerFull { max_players } => {
+                write!(f, "the server is full ({max_players} players)")
+            }
+        }
+    }
+}
+
+/// sent right after the hello, the server only reads it if the hello checks out
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Login {
+    pub name: String,
+    /// the id we had before losing the connection, the server hands it back if it still has it
+    pub previous_id: Option<PlayerId>,
+}
+
+/// strips anything that can't be drawn above a ghost, `None` if nothing is left
+pub fn sanitize_name(name: &str) -> Option<String> {
+    let name = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_LEN)
+        .collect::<String>();
+    let name = name.trim();
+
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// what the server handed out to a client that passed the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Session {
+    pub id: PlayerId,
+    pub capabilities: Capabilities,
+}
+
+/// why `client_handshake` failed
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandshakeError {
+    /// the server turned us away, trying again won't change that
+    Rejected(RejectReason),
+    /// the server runs a version of the protocol too old for us
+    VersionMismatch(u16),
+    /// the connection broke before the handshake was done
+    Io(String),
+}
+
+impl Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rejected(reason) => write!(f, "rejected by server : {reason}"),
+            Self::VersionMismatch(version) => write!(
+                f,
+                "server answered with protocol v{version} but we need at least v{MIN_COMPATIBLE_VERSION}"
+            ),
+            Self::Io(err) => f.write_str(err),
+        }
+    }
+}
+
+impl From<io::Error> for HandshakeError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err.to_string())
+    }
+}
+
+/// sends a hello and waits for the server to accept it
+pub fn client_handshake(
+    stream: &mut dyn Connection,
+    login: &Login,
+) -> Result<Session, HandshakeError> {
+    client_handshake_as(stream, &Hello::new(), login)
+}
+
+/// `client_handshake` introducing ourselves with `hello`, lets tests pretend to be another version
+pub fn client_handshake_as(
+    stream: &mut dyn Connection,
+    hello: &Hello,
+    login: &Login,
+) -> Result<Session, HandshakeError> {
+    stream.send(hello)?;
+    stream.send(login)?;
+
+    match stream.recv()? {
+        HandshakeReply::Welcome {
+            version,
+            capabilities,
+            id,
+        } if version >= MIN_COMPATIBLE_VERSION => Ok(Session { id, capabilities }),
+        HandshakeReply::Welcome { version, .. } => Err(HandshakeError::VersionMismatch(version)),
+        HandshakeReply::Rejected(reason) => Err(HandshakeError::Rejected(reason)),
+    }
+}
+
+/// the server side of the handshake, can be polled on a connection that doesn't block
+#[derive(Debug)]
+pub struct ServerHandshake {
+    offered: Capabilities,
+    hello: Option<Hello>,
+}
+
+impl ServerHandshake {
+    pub fn new(offered: Capabilities) -> Self {
+        Self {
+            offered,
+            hello: None,
+        }
+    }
+
+    /// `None` until the hello and the login came in, rejected peers are told why before this fails
+    ///
+    /// `assign_id` is only called once the hello checks out
+    pub fn poll(
+        &mut self,
+        stream: &mut dyn Connection,
+        assign_id: impl FnOnce(Login) -> Result<PlayerId, RejectReason>,
+    ) -> Result<Option<Session>, String> {
+        let hello = match self.hello.take() {
+            Some(hello) => hello,
+            None => {
+                let Some(hello) = recv_or_wait::<Hello>(stream)? else {
+                    return Ok(None);
+                };
+
+                if let Err(reason) = hello.check() {
+                    _ = stream.send(&HandshakeReply::Rejected(reason.clone()));
+                    return Err(reason.to_string());
+                }
+
+                hello
+            }
+        };
+        let hello = self.hello.insert(hello); // kept around in case the login isn't there yet
+
+        let Some(login) = recv_or_wait::<Login>(stream)? else {
+            return Ok(None);
+        };
+
+        let id = match assign_id(login) {
+            Ok(id) => id,
+            Err(reason) => {
+                _ = stream.send(&HandshakeReply::Rejected(reason.clone()));
+                return Err(reason.to_string());
+            }
+        };
+
+        let capabilities = hello.capabilities.intersection(self.offered);
+
+        stream
+            .send(&HandshakeReply::Welcome {
+                version: PROTOCOL_VERSION,
+                capabilities,
+                id,
+            })
+            .map_err(|err| err.to_string())?;
+
+        Ok(Some(Session { id, capabilities }))
+    }
+}
+
+/// `None` if nothing came in before the read timed out
+fn recv_or_wait<T: DeserializeOwned>(stream: &mut dyn Connection) -> Result<Option<T>, String> {
+    match stream.recv() {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if is_timeout(&err) => Ok(None),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// how positions travel between the client and the server, picked per server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PositionTransport {
+    /// in order and reliable on the same stream as everything else
+    #[default]
+    Tcp,
+    /// unreliable datagrams, late ones are dropped
+    Udp,
+}
+
+impl PositionTransport {
+    pub fn capabilities(self) -> Capabilities {
+        match self {
+            Self::Tcp => Capabilities::local().without(Capabilities::UDP_POSITIONS),
+            Self::Udp => Capabilities::local(),
+        }
+    }
+}
+
+impl FromStr for PositionTransport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "tcp" => Ok(Self::Tcp),
+            "udp" => Ok(Self::Udp),
+            _ => Err(format!("{s} isn't a transport, expected tcp or udp")),
+        }
+    }
+}
+
+/// biggest datagram either side will send or read
+pub const MAX_DATAGRAM_SIZE: usize = 8 * 1024;
+
+/// what goes over udp when `Capabilities::UDP_POSITIONS` was agreed on
+///
+/// datagrams can arrive late, twice or never, positions are told apart by `PlayerInfo::sequence`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Datagram {
+    Position {
+        id: PlayerId,
+        info: PlayerInfo,
+    },
+    /// the tick doubles as the sequence number
+    Snapshot(SnapshotDelta),
+}
+
+/// whether `sequence` came after `last`, survives the counter wrapping around
+pub fn is_newer(sequence: u32, last: u32) -> bool {
+    (sequence.wrapping_sub(last) as i32) > 0
+}
+
+/// hands out the sequence numbers and timestamps our own positions go out with
+#[derive(Debug)]
+pub struct Stamper {
+    started: Instant,
+    sequence: AtomicU32,
+}
+
+impl Stamper {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            sequence: AtomicU32::new(0),
+        }
+    }
+
+    /// `info` with the next sequence number and how long we've been running
+    pub fn stamp(&self, mut info: PlayerInfo) -> PlayerInfo {
+        info.sequence = self
+            .sequence
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_add(1);
+        info.sent_at = self.started.elapsed();
+        info
+    }
+}
+
+impl Default for Stamper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// how many times a second positions and snapshots go out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TickRate(u32);
+
+impl TickRate {
+    pub const MAX: u32 = 128;
+
+    pub fn new(hz: u32) -> Result<Self, String> {
+        if (1..=Self::MAX).contains(&hz) {
+            Ok(Self(hz))
+        } else {
+            Err(format!(
+                "the tick rate has to be between 1 and {} hz",
+                Self::MAX
+            ))
+        }
+    }
+
+    pub fn hz(self) -> u32 {
+        self.0
+    }
+
+    pub fn interval(self) -> Duration {
+        Duration::from_secs(1) / self.0
+    }
+
+    /// when the tick after the one that was due at `due` should run, ticks that were missed are skipped
+    pub fn next_tick(self, due: Instant) -> Instant {
+        let next = due + self.interval();
+        let now = Instant::now();
+
+        if next < now {
+            now + self.interval()
+        } else {
+            next
+        }
+    }
+}
+
+impl Default for TickRate {
+    fn default() -> Self {
+        Self(DEFAULT_TICK_RATE)
+    }
+}
+
+impl FromStr for TickRate {
+    type Err = String;
+
+    /// needs the unit so it can't be mistaken for any other number, like `60hz`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hz = s
+            .to_lowercase()
+            .strip_suffix("hz")
+            .and_then(|hz| hz.parse().ok())
+            .ok_or_else(|| format!("{s} isn't a tick rate, expected something like 30hz"))?;
+
+        Self::new(hz)
+    }
+}
+
+/// how long connections wait on their peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// how long a single read blocks before the heartbeat gets checked
+    pub read: Duration,
+    pub write: Duration,
+    pub ping_interval: Duration,
+    /// how long a peer can stay silent before the connection is closed
+    pub peer: Duration,
+    /// how long opening a connection can take
+    pub connect: Duration,
+}
+
+impl Timeouts {
+    pub fn apply(&self, connection: &mut dyn Connection) -> io::Result<()> {
+        connection.set_read_timeout(Some(self.read))?;
+        connection.set_write_timeout(Some(self.write))
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            read: Duration::from_millis(250),
+            write: Duration::from_secs(1),
+            ping_interval: Duration::from_secs(1),
+            peer: Duration::from_secs(5),
+            connect: Duration::from_secs(3),
+        }
+    }
+}
+
+/// tracks when the peer was last heard from and when it should be pinged next
+#[derive(Debug)]
+pub struct Heartbeat {
+    timeouts: Timeouts,
+    last_received: Instant,
+    last_ping: Instant,
+    nonce: u64,
+}
+
+impl Heartbeat {
+    pub fn new(timeouts: Timeouts) -> Self {
+        Self {
+            timeouts,
+            last_received: Instant::now(),
+            last_ping: Instant::now(),
+            nonce: 0,
+        }
+    }
+
+    pub fn received(&mut self) {
+        self.last_received = Instant::now()
+    }
+
+    /// fails once the peer was silent for too long, otherwise returns a ping if one is due
+    pub fn poll(&mut self) -> Result<Option<Message>, String> {
+        let silent_for = self.last_received.elapsed();
+
+        if silent_for > self.timeouts.peer {
+            return Err(format!("peer was silent for {silent_for:?}"));
+        }
+
+        if self.last_ping.elapsed() < self.timeouts.ping_interval {
+            return Ok(None);
+        }
+
+        self.last_ping = Instant::now();
+        self.nonce += 1;
+        Ok(Some(Message::Ping(self.nonce)))
+    }
+}
+
+/// reads that ran into their timeout fail with `WouldBlock` or `TimedOut` depending on the platform
+pub fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// joins `thread` unless it's still running after `timeout`, in which case it's handed back
+pub fn join_timeout(thread: JoinHandle<()>, timeout: Duration) -> Result<(), JoinHandle<()>> {
+    let start = Instant::now();
+
+    while !thread.is_finished() {
+        if start.elapsed() > timeout {
+            return Err(thread);
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    _ = thread.join();
+    Ok(())
+}
+
+/// prefixes a payload with its length so it can be read back as one frame
+pub fn encode_frame(payload: &[u8]) -> io::Result<Vec<u8>> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("frame of {} bytes is too big", payload.len()),
+        ));
+    }
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// collects bytes from any amount of reads and splits them back into whole frames
+#[derive(Debug, Default)]
+pub struct FrameReader {
+    buffer: Vec<u8>,
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes)
+    }
+
+    /// returns the next complete frame or `None` if more bytes are needed
+    pub fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.buffer.len() < FRAME_HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let mut header = [0; FRAME_HEADER_SIZE];
+        header.copy_from_slice(&self.buffer[..FRAME_HEADER_SIZE]);
+        let len = u32::from_le_bytes(header) as usize;
+
+        if len > MAX_FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("incoming frame of {len} bytes is too big"),
+            ));
+        }
+
+        if self.buffer.len() < FRAME_HEADER_SIZE + len {
+            return Ok(None);
+        }
+
+        let frame = self.buffer[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + len].to_vec();
+        self.buffer.drain(..FRAME_HEADER_SIZE + len);
+        Ok(Some(frame))
+    }
+}
+
+/// see `Connection::closer`
+pub struct Closer(Box<dyn FnOnce() + Send>);
+
+impl Closer {
+    pub fn new(close: impl FnOnce() + Send + 'static) -> Self {
+        Self(Box::new(close))
+    }
+
+    pub fn close(self) {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for Closer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Closer")
+    }
+}
+
+/// opens a new connection to the same peer, used to reconnect after losing one
+///
+/// the flag handed to the dial is set once nobody waits for it anymore, it should give up as soon as it can
+pub struct Dialer(Box<Dial>);
+
+type Dial = dyn FnMut(&AtomicBool) -> io::Result<Box<dyn Connection>> + Send;
+
+impl Dialer {
+    pub fn new(
+        dial: impl FnMut(&AtomicBool) -> io::Result<Box<dyn Connection>> + Send + 'static,
+    ) -> Self {
+        Self(Box::new(dial))
+    }
+
+    /// tries every address `address` resolves to until `timeout` ran out for all of them together
+    ///
+    /// an address that's already being tried can't be given up on, so an aborted dial can still take
+    /// what's left of the timeout
+    pub fn tcp(address: String, timeout: Duration) -> Self {
+        Self::new(move |aborted| {
+            let deadline = Instant::now() + timeout;
+            let mut last_err = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{address} doesn't resolve to anything"),
+            );
+
+            for addr in address.to_socket_addrs()? {
+                if aborted.load(Ordering::Relaxed) {
+                    return Err(io::ErrorKind::Interrupted.into());
+                }
+
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+
+                match TcpStream::connect_timeout(&addr, left) {
+                    Ok(stream) => {
+                        return Ok(Box::new(FramedStream::new(stream)) as Box<dyn Connection>)
+                    }
+                    Err(err) => last_err = err,
+                }
+            }
+
+            Err(last_err)
+        })
+    }
+
+    pub fn dial(&mut self, aborted: &AtomicBool) -> io::Result<Box<dyn Connection>> {
+        (self.0)(aborted)
+    }
+}
+
+impl fmt::Debug for Dialer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Dialer")
+    }
+}
+
+/// a reliable and ordered stream of frames to one peer
+///
+/// the workers only talk through this so they don't care what carries the bytes
+pub trait Connection: Send + fmt::Debug {
+    fn send_frame(&mut self, payload: &[u8]) -> io::Result<()>;
+
+    /// blocks until a whole frame arrived, fails with a timeout error once the read timeout runs out
+    fn recv_frame(&mut self) -> io::Result<Vec<u8>>;
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// reads fail with `WouldBlock` right away instead of waiting and writes get queued up
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()>;
+
+    /// writes whatever got queued up while the peer wasn't ready for it
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// where the peer can be reached outside of this connection, `None` if it can't
+    fn peer_addr(&self) -> Option<SocketAddr>;
+
+    /// something that closes this connection from another thread, waking up a blocked read
+    fn closer(&self) -> Option<Closer>;
+
+    /// tells the peer nothing else is coming
+    fn close(&mut self);
+}
+
+/// (de)serializing on top of any `Connection`
+pub trait ConnectionExt: Connection {
+    fn send<T: Serialize>(&mut self, value: &T) -> io::Result<()> {
+        let payload = bincode::serialize(value)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        self.send_frame(&payload)
+    }
+
+    fn recv<T: DeserializeOwned>(&mut self) -> io::Result<T> {
+        let frame = self.recv_frame()?;
+        bincode::deserialize(&frame).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// like `recv` but frames that don't decode into a known `Message` are skipped instead of failing
+    fn recv_message(&mut self) -> io::Result<Option<Message>> {
+        let frame = self.recv_frame()?;
+
+        match bincode::deserialize(&frame) {
+            Ok(message) => Ok(Some(message)),
+            Err(err) => {
+                log::warn!("skipping unknown message : {err}");
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl<C: Connection + ?Sized> ConnectionExt for C {}
+
+/// wraps a stream so that whole frames are read and written at once
+///
+/// partial frames stay buffered when a read fails so a retry picks up where it left off
+#[derive(Debug)]
+pub struct FramedStream<S> {
+    stream: S,
+    reader: FrameReader,
+    /// bytes the stream didn't take yet
+    backlog: Vec<u8>,
+}
+
+impl<S: Read + Write> FramedStream<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            reader: FrameReader::new(),
+            backlog: Vec::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// the frame is queued up if the stream can't take all of it right now
+    pub fn write_frame(&mut self, payload: &[u8]) -> io::Result<()> {
+        if self.backlog.len() > MAX_WRITE_BACKLOG {
+            return Err(io::Error::other("peer stopped reading what we send"));
+        }
+
+        self.backlog.extend(encode_frame(payload)?);
+        self.write_backlog()
+    }
+
+    /// writes as much of the backlog as the stream takes without blocking for too long
+    pub fn write_backlog(&mut self) -> io::Result<()> {
+        while !self.backlog.is_empty() {
+            match self.stream.write(&self.backlog) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => _ = self.backlog.drain(..written),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) if is_timeout(&err) => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+
+        self.stream.flush()
+    }
+
+    pub fn read_frame(&mut self) -> io::Result<Vec<u8>> {
+        let mut chunk = [0; 1024];
+
+        loop {
+            if let Some(frame) = self.reader.next_frame()? {
+                return Ok(frame);
+            }
+
+            match self.stream.read(&mut chunk)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                read => self.reader.extend(&chunk[..read]),
+            }
+        }
+    }
+}
+
+impl Connection for FramedStream<TcpStream> {
+    fn send_frame(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.write_frame(payload)
+    }
+
+    fn recv_frame(&mut self) -> io::Result<Vec<u8>> {
+        self.read_frame()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(timeout)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.stream.set_nonblocking(nonblocking)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_backlog()
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.stream.peer_addr().ok()
+    }
+
+    fn closer(&self) -> Option<Closer> {
+        let stream = self.stream.try_clone().ok()?;
+        Some(Closer::new(move || _ = stream.shutdown(Shutdown::Both)))
+    }
+
+    fn close(&mut self) {
+        _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// one end of a connection that never leaves the process, frames are handed over through a channel
+#[derive(Debug)]
+pub struct MemoryConnection {
+    sender: Option<Sender<Vec<u8>>>,
+    receiver: Receiver<Vec<u8>>,
+    read_timeout: Option<Duration>,
+    nonblocking: bool,
+}
+
+impl MemoryConnection {
+    /// two ends connected to each other
+    pub fn pair() -> (Self, Self) {
+        let (left_send, right_recv) = mpsc::channel();
+        let (right_send, left_recv) = mpsc::channel();
+
+        (
+            Self {
+                sender: Some(left_send),
+                receiver: left_recv,
+                read_timeout: None,
+                nonblocking: false,
+            },
+            Self {
+                sender: Some(right_send),
+                receiver: right_recv,
+                read_timeout: None,
+                nonblocking: false,
+            },
+        )
+    }
+}
+
+impl Connection for MemoryConnection {
+    fn send_frame(&mut self, payload: &[u8]) -> io::Result<()> {
+        if payload.len() > MAX_FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("frame of {} bytes is too big", payload.len()),
+            ));
+        }
+
+        self.sender
+            .as_ref()
+            .ok_or(io::ErrorKind::NotConnected)?
+            .send(payload.to_vec())
+            .or(Err(io::ErrorKind::BrokenPipe.into()))
+    }
+
+    fn recv_frame(&mut self) -> io::Result<Vec<u8>> {
+        if self.nonblocking {
+            return self.receiver.try_recv().map_err(|err| match err {
+                TryRecvError::Empty => io::ErrorKind::WouldBlock.into(),
+                TryRecvError::Disconnected => io::ErrorKind::UnexpectedEof.into(),
+            });
+        }
+
+        match self.read_timeout {
+            Some(timeout) => self
+                .receiver
+                .recv_timeout(timeout)
+                .map_err(|err| match err {
+                    RecvTimeoutError::Timeout => io::ErrorKind::TimedOut.into(),
+                    RecvTimeoutError::Disconnected => io::ErrorKind::UnexpectedEof.into(),
+                }),
+            None => self
+                .receiver
+                .recv()
+                .or(Err(io::ErrorKind::UnexpectedEof.into())),
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    fn set_write_timeout(&mut self, _: Option<Duration>) -> io::Result<()> {
+        Ok(()) // sending never blocks
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking = nonblocking;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    fn closer(&self) -> Option<Closer> {
+        None // there is no socket to shut down
+    }
+
+    fn close(&mut self) {
+        self.sender = None;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// how long tests wait on other threads before giving up
+    const WAIT_LIMIT: Duration = Duration::from_secs(5);
+
+    /// polls `done` until it's true, fails the test if that takes longer than `WAIT_LIMIT`
+    pub fn wait_until(mut done: impl FnMut() -> bool) {
+        wait_for(|| done().then_some(()))
+    }
+
+    /// polls `poll` until it comes up with something, fails the test if that takes longer than `WAIT_LIMIT`
+    pub fn wait_for<T>(mut poll: impl FnMut() -> Option<T>) -> T {
+        let start = Instant::now();
+        loop {
+            if let Some(value) = poll() {
+                return value;
+            }
+            assert!(start.elapsed() < WAIT_LIMIT, "gave up waiting");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn aborted_dials_give_up() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let mut dialer = Dialer::tcp(address, Duration::from_secs(1));
+        assert!(dialer.dial(&AtomicBool::new(false)).is_ok());
+        assert_eq!(
+            dialer.dial(&AtomicBool::new(true)).unwrap_err().kind(),
+            io::ErrorKind::Interrupted
+        );
+
+        let mut dialer = Dialer::tcp("localhost:1".to_string(), Duration::ZERO);
+        assert_eq!(
+            dialer.dial(&AtomicBool::new(false)).unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+
+    /// a stream that hands out its bytes a few at a time like a congested socket would
+    struct Trickle {
+        data: Cursor<Vec<u8>>,
+        step: usize,
+        written: Vec<u8>,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.step);
+            self.data.read(&mut buf[..len])
+        }
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn test_info(x: f32) -> PlayerInfo {
+        PlayerInfo::new(
+            Vector3::from([x, 2., 3.]),
+            Vector3::from([0., 90., 0.]),
+            Vector3::from([300., 0., 0.]),
+            Action::Run,
+        )
+    }
+
+    #[test]
+    fn fragmented_frames_are_reassembled() {
+        let frame = encode_frame(&bincode::serialize(&test_info(1.)).unwrap()).unwrap();
+        let mut reader = FrameReader::new();
+
+        for byte in &frame[..frame.len() - 1] {
+            reader.extend(&[*byte]);
+            assert!(reader.next_frame().unwrap().is_none());
+        }
+        reader.extend(&frame[frame.len() - 1..]);
+
+        let payload = reader.next_frame().unwrap().unwrap();
+        let info: PlayerInfo = bincode::deserialize(&payload).unwrap();
+        assert_eq!(info, test_info(1.));
+    }
+
+    #[test]
+    fn merged_frames_are_split() {
+        let mut bytes = Vec::new();
+        for x in 0..3 {
+            bytes.extend(encode_frame(&bincode::serialize(&test_info(x as f32)).unwrap()).unwrap());
+        }
+        bytes.extend(encode_frame(&bincode::serialize(&vec![test_info(9.); 16]).unwrap()).unwrap());
+
+        let mut reader = FrameReader::new();
+        reader.extend(&bytes);
+
+        for x in 0..3 {
+            let info: PlayerInfo =
+                bincode::deserialize(&reader.next_frame().unwrap().unwrap()).unwrap();
+            assert_eq!(info, test_info(x as f32));
+        }
+        let infos: Vec<PlayerInfo> =
+            bincode::deserialize(&reader.next_frame().unwrap().unwrap()).unwrap();
+        assert_eq!(infos, vec![test_info(9.); 16]);
+        assert!(reader.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn framed_stream_reads_across_short_reads() {
+        let mut bytes = Vec::new();
+        for x in 0..5 {
+            bytes.extend(encode_frame(&bincode::serialize(&test_info(x as f32)).unwrap()).unwrap());
+        }
+
+        let mut stream = FramedStream::new(Trickle {
+            data: Cursor::new(bytes),
+            step: 3,
+            written: Vec::new(),
+        });
+
+        for x in 0..5 {
+            let info: PlayerInfo = bincode::deserialize(&stream.read_frame().unwrap()).unwrap();
+            assert_eq!(info, test_info(x as f32));
+        }
+        assert_eq!(
+            stream.read_frame().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn unknown_messages_are_skipped() {
+        let (mut left, mut right) = MemoryConnection::pair();
+
+        left.send_frame(&u32::MAX.to_le_bytes()).unwrap(); // variant index nobody has
+        left.send(&Message::Ping(7)).unwrap();
+
+        assert_eq!(right.recv_message().unwrap(), None);
+        assert_eq!(right.recv_message().unwrap(), Some(Message::Ping(7)));
+    }
+
+    #[test]
+    fn memory_connections_time_out_and_close() {
+        let (mut left, mut right) = MemoryConnection::pair();
+        right
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+
+        assert!(is_timeout(&right.recv_frame().unwrap_err()));
+
+        left.send(&Message::Pong(1)).unwrap();
+        left.close();
+
+        assert_eq!(right.recv_message().unwrap(), Some(Message::Pong(1)));
+        assert_eq!(
+            right.recv_frame().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert!(left.send_frame(&[]).is_err());
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        assert!(is_newer(1, 0));
+        assert!(!is_newer(0, 1));
+        assert!(!is_newer(5, 5));
+        assert!(is_newer(2, u32::MAX - 2));
+        assert!(!is_newer(u32::MAX - 2, 2));
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let mut reader = FrameReader::new();
+        reader.extend(&(MAX_FRAME_SIZE as u32 + 1).to_le_bytes());
+        assert!(reader.next_frame().is_err());
+        assert!(encode_frame(&vec![0; MAX_FRAME_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn switching_modes_cleans_up() {
+        let mut host = PlayerMirrorServer::new();
+        host.bind("127.0.0.1:0".to_string()).unwrap();
+        let address = host.local_addr().unwrap().to_string();
+
+        let mut mirror = MirroringType::Client(PlayerMirrorClient::new());
+
+        for _ in 0..100 {
+            mirror.client().connect(address.clone()).unwrap();
+            mirror.server().bind("127.0.0.1:0".to_string()).unwrap();
+        }
+
+        assert!(mirror.server().players.read().is_ok());
+        assert!(mirror.client().players.read().is_ok());
+
+        // every client said goodbye instead of just vanishing
+        wait_until(|| host.players.read().unwrap().roster().count() == 1);
+    }
+
+    #[test]
+    fn switching_modes_hides_the_old_dummies() {
+        let mut mirror = MirroringType::Client(PlayerMirrorClient::new());
+
+        let client = mirror.client();
+        let players = [(1, test_info(1.)), (2, test_info(2.))];
+        client.players.write().unwrap().interpolation.push(
+            Duration::ZERO,
+            &players,
+            Instant::now(),
+        );
+        assert_eq!(client.get_other_positions().unwrap().len(), 2);
+
+        let mut left = mirror.server().take_left_ghosts();
+        left.sort();
+        assert_eq!(left, vec![0, 1]);
+        assert!(mirror.server().take_left_ghosts().is_empty());
+    }
+
+    fn snapshot(tick: u32, players: &[(PlayerId, f32)]) -> Snapshot {
+        Snapshot {
+            tick,
+            time: Duration::from_millis(tick as u64 * 33),
+            players: players.iter().map(|(id, x)| (*id, test_info(*x))).collect(),
+        }
+    }
+
+    #[test]
+    fn quantizing_rounds_to_the_wire_precision() {
+        let info = PlayerInfo::new(
+            Vector3::from([11356.03, -2619.5, -204.]),
+            Vector3::from([-45., 270., 540.]),
+            Vector3::from([0., 0., 0.]),
+            Action::WallrunLeft,
+        );
+
+        let quantized = QuantizedInfo::from(info);
+        assert_eq!(quantized.position, [181696, -41912, -3264]);
+
+        let info = PlayerInfo::from(quantized);
+        assert_eq!(
+            info.position,
+            SerializableVector3 {
+                x: 11356.,
+                y: -2619.5,
+                z: -204.
+            }
+        );
+        assert_eq!(
+            info.viewangle,
+            SerializableVector3 {
+                x: -45.,
+                y: -90.,
+                z: -180.
+            }
+        );
+        assert_eq!(info.action, Action::WallrunLeft);
+    }
+
+    #[test]
+    fn deltas_only_carry_changes() {
+        let mut sent = SnapshotHistory::new();
+        let mut received = SnapshotHistory::new();
+
+        sent.push(snapshot(1, &[(0, 1.), (1, 1.), (2, 1.)]));
+        let full = sent.delta(None).unwrap();
+        assert_eq!(full.changed.len(), 3);
+        assert_eq!(
+            received.apply(full).unwrap(),
+            snapshot(1, &[(0, 1.), (1, 1.), (2, 1.)])
+        );
+
+        // 1 moved, 2 left and 3 joined
+        sent.push(snapshot(2, &[(0, 1.), (1, 2.), (3, 1.)]));
+        let delta = sent.delta(sent.baseline(Some(1))).unwrap();
+        assert_eq!(delta.baseline, Some(1));
+        assert_eq!(
+            delta.changed,
+            vec![(1, test_info(2.).into()), (3, test_info(1.).into())]
+        );
+        assert_eq!(delta.removed, vec![2]);
+
+        assert_eq!(
+            received.apply(delta).unwrap(),
+            snapshot(2, &[(0, 1.), (1, 2.), (3, 1.)])
+        );
+    }
+
+    #[test]
+    fn deltas_against_forgotten_snapshots_fail() {
+        let mut sent = SnapshotHistory::new();
+        for tick in 0..=SNAPSHOT_HISTORY as u32 {
+            sent.push(snapshot(tick, &[(0, tick as f32)]));
+        }
+
+        // the oldest one fell out so there's nothing to make a delta against
+        assert_eq!(sent.baseline(Some(0)), None);
+        assert_eq!(sent.delta(Some(0)).unwrap().baseline, None);
+
+        let delta = sent.delta(Some(1)).unwrap();
+        assert_eq!(delta.baseline, Some(1));
+        assert!(SnapshotHistory::new().apply(delta).is_err());
+    }
+
+    #[test]
+    fn restamped_players_that_didnt_move_stay_out_of_deltas() {
+        let stamper = Stamper::new();
+        let mut sent = SnapshotHistory::new();
+
+        let first = stamper.stamp(test_info(1.));
+        sent.push(Snapshot {
+            tick: 1,
+            players: vec![(0, first.clone())],
+            ..Default::default()
+        });
+        assert_eq!(sent.delta(None).unwrap().changed[0].1.sequence, 1);
+
+        let second = stamper.stamp(test_info(1.));
+        assert!(is_newer(second.sequence, first.sequence));
+        sent.push(Snapshot {
+            tick: 2,
+            players: vec![(0, second)],
+            ..Default::default()
+        });
+        assert!(sent.delta(Some(1)).unwrap().changed.is_empty());
+    }
+
+    #[test]
+    fn stale_updates_dont_reach_ghosts() {
+        let mut interpolation = Interpolation::new();
+        let start = Instant::now();
+        let ms = |ms: u64| Duration::from_millis(ms);
+        let stamped = |x: f32, sequence: u32| PlayerInfo {
+            sequence,
+            ..at_x(x)
+        };
+
+        interpolation.push(ms(0), &[(1, stamped(0., 7))], start);
+        interpolation.push(ms(33), &[(1, stamped(33., 8))], start + ms(33));
+        // an older update relayed after a newer one
+        interpolation.push(ms(66), &[(1, stamped(-500., 6))], start + ms(66));
+
+        let ghost = &interpolation.sample(start + ms(166))[0].1;
+        assert_eq!(ghost.position.x, 33.);
+        assert_eq!(ghost.sequence, 8);
+    }
+
+    fn at_x(x: f32) -> PlayerInfo {
+        PlayerInfo {
+            position: SerializableVector3 { x, y: 0., z: 0. },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn ghosts_are_blended_between_snapshots() {
+        let mut interpolation = Interpolation::new();
+        let start = Instant::now();
+        let ms = |ms: u64| Duration::from_millis(ms);
+
+        interpolation.push(ms(1000), &[(1, at_x(0.))], start);
+        interpolation.push(ms(1100), &[(1, at_x(10.))], start + ms(100));
+        interpolation.push(ms(1200), &[(1, at_x(20.))], start + ms(200));
+
+        // 100ms behind the newest snapshot and then 50ms further along
+        assert_eq!(interpolation.sample(start + ms(200)), vec![(1, at_x(10.))]);
+        let [(1, info)] = &interpolation.sample(start + ms(250))[..] else {
+            panic!("expected one player");
+        };
+        assert!((info.position.x - 15.).abs() < 0.01);
+
+        // nothing newer came in so the last one is held
+        assert_eq!(interpolation.sample(start + ms(900)), vec![(1, at_x(20.))]);
+
+        interpolation.push(ms(1300), &[], start + ms(300));
+        assert!(interpolation.sample(start + ms(300)).is_empty());
+    }
+
+    #[test]
+    fn jitter_doesnt_move_the_clock() {
+        let mut interpolation = Interpolation::new();
+        let start = Instant::now();
+        let ms = |ms: u64| Duration::from_millis(ms);
+
+        // sent every 33ms but showing up anywhere from on time to 30ms late
+        for (tick, late) in [0, 30, 5, 25, 0, 10, 30, 0, 20, 5].into_iter().enumerate() {
+            let sent = tick as u64 * 33;
+            interpolation.push(ms(sent), &[(1, at_x(sent as f32))], start + ms(sent + late));
+        }
+
+        // the ghost keeps moving at the speed it moved on the server
+        let positions = (0..4)
+            .map(|frame| {
+                let now = start + ms(330 + frame * 16);
+                interpolation.sample(now)[0].1.position.x
+            })
+            .collect::<Vec<_>>();
+
+        for pair in positions.windows(2) {
+            assert!((pair[1] - pair[0] - 16.).abs() < 0.5, "{positions:?}");
+        }
+    }
+
+    #[test]
+    fn angles_are_blended_the_short_way_around() {
+        let from = SerializableVector3 {
+            x: 0.,
+            y: 170.,
+            z: 0.,
+        };
+        let to = SerializableVector3 {
+            x: 0.,
+            y: -170.,
+            z: 0.,
+        };
+
+        let halfway = from.lerp_angles(&to, 0.5);
+        assert!((halfway.y - 180.).abs() < 0.01);
+    }
+
+    /// someone running along x at 300 units per second who stops at `stops_at` milliseconds
+    fn runner(sent: u64, stops_at: u64) -> PlayerInfo {
+        let x = 0.3 * sent.min(stops_at) as f32;
+
+        PlayerInfo::new(
+            Vector3::from([x, 0., 0.]),
+            Vector3::from([0., 0., 0.]),
+            Vector3::from([if sent < stops_at { 300. } else { 0. }, 0., 0.]),
+            if sent < stops_at {
+                Action::Run
+            } else {
+                Action::Stand
+            },
+        )
+    }
+
+    /// hands `interpolation` every snapshot once it arrived and returns where the ghost was every frame
+    ///
+    /// `snapshots` is when each one was sent and when it showed up in milliseconds
+    fn replay(
+        interpolation: &mut Interpolation,
+        snapshots: &[(u64, u64)],
+        stops_at: u64,
+        until: u64,
+    ) -> Vec<(u64, f32)> {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+
+        let mut arrivals = snapshots.to_vec();
+        arrivals.sort_by_key(|(_, arrived)| *arrived);
+        let mut arrivals = arrivals.into_iter().peekable();
+
+        (0..until)
+            .step_by(16)
+            .map(|frame| {
+                while let Some((sent, _)) = arrivals.next_if(|(_, arrived)| *arrived <= frame) {
+                    interpolation.push(ms(sent), &[(1, runner(sent, stops_at))], start + ms(frame));
+                }
+
+                (
+                    frame,
+                    interpolation.sample(start + ms(frame))[0].1.position.x,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn late_snapshots_are_extrapolated() {
+        // up to 20ms of jitter and then nothing for 200ms before the stuck ones come in all at once
+        let snapshots = (0..30)
+            .map(|tick| {
+                let sent = tick * 33;
+                let arrived = match sent {
+                    330..=528 => 560,
+                    _ => sent + [0, 20, 5, 15, 10][tick as usize % 5],
+                };
+                (sent, arrived)
+            })
+            .collect::<Vec<_>>();
+
+        let frames = replay(&mut Interpolation::new(), &snapshots, u64::MAX, 900);
+
+        // the ghost never stops or jumps even though nothing came in for a while
+        for pair in frames.windows(2).skip_while(|pair| pair[0].0 < 150) {
+            let step = pair[1].1 - pair[0].1;
+            assert!((2.5..=7.5).contains(&step), "{pair:?} in {frames:?}");
+        }
+    }
+
+    #[test]
+    fn extrapolation_blends_back_to_where_players_really_are() {
+        // they stop right when the snapshots stop showing up
+        let snapshots = (0..30)
+            .map(|tick| {
+                let sent = tick * 33;
+                (
+                    sent,
+                    if (330..=660).contains(&sent) {
+                        800
+                    } else {
+                        sent
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut interpolation = Interpolation::new();
+        let frames = replay(&mut interpolation, &snapshots, 330, 1200);
+        let stopped = 0.3 * 330.;
+        let overshoot = 0.3 * Extrapolation::default().max.as_millis() as f32;
+
+        let furthest = frames.iter().map(|(_, x)| *x).fold(0., f32::max);
+        assert!(furthest > stopped + overshoot / 2., "{frames:?}");
+        assert!(furthest <= stopped + overshoot + 1., "{frames:?}");
+
+        // eased back instead of snapping
+        for pair in frames.windows(2) {
+            assert!(
+                (pair[1].1 - pair[0].1).abs() < 15.,
+                "{pair:?} in {frames:?}"
+            );
+        }
+        assert!((frames.last().unwrap().1 - stopped).abs() < 0.01);
+
+        // without a blend they snap right back once the snapshots show up
+        let mut interpolation = Interpolation::new();
+        interpolation.set_extrapolation(Extrapolation {
+            blend: Duration::ZERO,
+            ..Default::default()
+        });
+        let frames = replay(&mut interpolation, &snapshots, 330, 1200);
+        assert!(frames.iter().all(|(_, x)| x.is_finite()), "{frames:?}");
+        assert!(frames
+            .iter()
+            .any(|(frame, x)| *frame >= 800 && (x - stopped).abs() < 0.01));
+    }
+
+    #[test]
+    fn airborne_players_fall_while_extrapolated() {
+        let info = PlayerInfo::new(
+            Vector3::from([0., 0., 100.]),
+            Vector3::from([0., 0., 0.]),
+            Vector3::from([100., 0., 0.]),
+            Action::Jump,
+        );
+
+        let later = info.extrapolate(0.2);
+        assert_eq!(later.position.x, 20.);
+        assert!((later.position.z - (100. - GRAVITY * 0.02)).abs() < 0.01);
+        assert!((later.velocity.z + GRAVITY * 0.2).abs() < 0.01);
+
+        let standing = PlayerInfo {
+            velocity: SerializableVector3 {
+                x: 5.,
+                y: 0.,
+                z: 0.,
+            },
+            ..Default::default()
+        };
+        assert_eq!(standing.extrapolate(0.2), standing);
+    }
+
+    #[test]
+    fn actions_keep_their_number() {
+        for value in -1..=20 {
+            assert_eq!(i32::from(Action::or_unknown(value)), value);
+        }
+
+        assert_eq!(Action::try_from(8), Ok(Action::Slide));
+        assert_eq!(Action::try_from(17), Ok(Action::PhaseShift));
+        assert_eq!(Action::try_from(18), Err(UnknownAction(18)));
+        assert_eq!(Action::try_from(-1), Err(UnknownAction(-1)));
+        assert_eq!(Action::or_unknown(18), Action::Unknown(18));
+    }
+
+    #[test]
+    fn unknown_actions_survive_the_wire() {
+        let info = QuantizedInfo::from(PlayerInfo {
+            action: Action::Unknown(40),
+            ..Default::default()
+        });
+        let bytes = bincode::serialize(&info).unwrap();
+        let decoded: QuantizedInfo = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(decoded.action, Action::Unknown(40));
+
+        // known ones are just their number
+        assert_eq!(
+            bincode::serialize(&Action::Zipline).unwrap(),
+            14i32.to_le_bytes()
+        );
+    }
+}
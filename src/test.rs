@@ -4,7 +4,10 @@ use rrplug::prelude::wait;
 use rrplug::wrappers::vector::Vector3;
 
 // use crate::client::PlayerMirrorClient;
-use crate::{server::PlayerMirrorServer, shared::PlayerInfo};
+use crate::{
+    server::PlayerMirrorServer,
+    shared::{Action, Ghost, PlayerInfo},
+};
 use log::{Level, LevelFilter, Metadata, Record};
 
 mod client;
@@ -76,12 +79,13 @@ fn main() {
     let fakeinfo = PlayerInfo::new(
         Vector3::from([11356., -2619., -204.]),
         Vector3::from([0., 0., 0.]),
-        2.try_into().unwrap(),
+        Vector3::from([0., 0., 0.]),
+        Action::Stand,
     );
 
     server.push_position_to_streams(fakeinfo.clone()).unwrap();
 
-    let mut saved_pos: [PlayerInfo; 16] = server.get_positions_from_streams().unwrap();
+    let mut saved_pos: Vec<Ghost> = server.get_positions_from_streams().unwrap();
 
     server
         .push_position_to_streams(fakeinfo)
@@ -90,18 +94,12 @@ fn main() {
     loop {
         let positions = server.get_positions_from_streams().unwrap();
 
-        for (index, pos) in saved_pos
-            .iter()
-            .enumerate()
-            .filter(|e| &positions[e.0] != e.1)
-        {
-            println!("{index} changed to {pos:?}");
+        for ghost in positions.iter().filter(|ghost| !saved_pos.contains(ghost)) {
+            println!("{} changed to {:?}", ghost.id, ghost.info);
         }
 
         saved_pos = positions;
 
-        _ = server.accept_connection();
-
         wait(100);
     }
 }